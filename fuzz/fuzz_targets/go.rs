@@ -0,0 +1,21 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use md2tgmdv2::{Converter, ConverterConfig};
+
+// The first byte picks the chunk size and whether tables are enabled, the
+// rest is the Markdown. `go` must return, Ok or Err, without panicking.
+fuzz_target!(|data: &[u8]| {
+    let Some((&setup, markdown)) = data.split_first() else {
+        return;
+    };
+    let Ok(markdown) = std::str::from_utf8(markdown) else {
+        return;
+    };
+    let mut converter = Converter::with_config(ConverterConfig {
+        max_len: 16 + usize::from(setup & 0x7f),
+        enable_tables: setup & 0x80 != 0,
+        ..Default::default()
+    });
+    let _ = converter.go(markdown);
+});
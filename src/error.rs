@@ -0,0 +1,11 @@
+//! Typed errors carried inside the `anyhow::Error`s returned by the converter.
+
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum Error {
+    /// A token that cannot be split is longer than a whole chunk, or the
+    /// prefixes and closers every chunk repeats leave no room for text.
+    #[error("a chunk needs at least {needed} to fit its text, but max_len is {max_len}")]
+    ChunkTooSmall { needed: usize, max_len: usize },
+}
@@ -0,0 +1,301 @@
+//! Escaping of text for the different MarkdownV2 entity contexts.
+
+use crate::{ConverterConfig, Dialect, EscapeMode};
+
+/// Characters Telegram MarkdownV2 treats as formatting control.
+const ESCAPED_CHARS: &str = "\\*_[]()~`>#+-=|{}.!";
+
+/// Where escaped text ends up; Telegram requires different escapes in each.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntityContext {
+    /// Regular prose.
+    Text,
+    /// Inline code.
+    Code,
+    /// The body of a code block.
+    Pre,
+    /// The `(...)` part of an inline link.
+    LinkUrl,
+    /// The `[...]` part of an inline link.
+    LinkLabel,
+}
+
+impl EntityContext {
+    /// The context with the same escaping rules that the converter uses.
+    fn rules(self) -> Self {
+        match self {
+            EntityContext::Pre => EntityContext::Code,
+            EntityContext::LinkLabel => EntityContext::Text,
+            context => context,
+        }
+    }
+}
+
+/// Escape `text` with the least escaping MarkdownV2 requires in `context`,
+/// for building messages by hand.
+pub fn escape_for(context: EntityContext, text: &str) -> String {
+    let config = ConverterConfig {
+        escape_mode: EscapeMode::Minimal,
+        ..Default::default()
+    };
+    escape_in(text, context, &config)
+}
+
+pub(crate) fn needs_escape(ch: char) -> bool {
+    ESCAPED_CHARS.contains(ch)
+}
+
+/// Whether the character at byte `idx` of `text` must be escaped in
+/// `context`, taking the options of `config` into account.
+fn must_escape(
+    text: &str,
+    idx: usize,
+    ch: char,
+    context: EntityContext,
+    config: &ConverterConfig,
+) -> bool {
+    let context = context.rules();
+    if config.dialect == Dialect::MarkdownV1 {
+        // Legacy Markdown escapes only these, and only outside entities.
+        return context == EntityContext::Text && matches!(ch, '_' | '*' | '`' | '[');
+    }
+    let required = match (config.escape_mode, context) {
+        // See `escape_code_content`.
+        (_, EntityContext::Code | EntityContext::Pre) => matches!(ch, '`' | '\\'),
+        (EscapeMode::Conservative, _)
+        | (EscapeMode::Minimal, EntityContext::Text | EntityContext::LinkLabel) => needs_escape(ch),
+        (EscapeMode::Minimal, EntityContext::LinkUrl) => matches!(ch, ')' | '\\'),
+    };
+    if !required {
+        return false;
+    }
+    if config.detect_pre_escaped && is_pre_escaped(text, idx, ch) {
+        return false;
+    }
+    !(context == EntityContext::Text
+        && ch == '#'
+        && config.preserve_hashtags
+        && is_hashtag_start(text, idx))
+}
+
+/// Whether the character at byte `idx` is part of an existing MarkdownV2
+/// escape: either an escaping backslash before a control character, or the
+/// control character following one. Backslash runs pair up left to right, so
+/// `\\.` is an escaped backslash followed by a bare dot.
+fn is_pre_escaped(text: &str, idx: usize, ch: char) -> bool {
+    let preceding = text[..idx]
+        .bytes()
+        .rev()
+        .take_while(|&b| b == b'\\')
+        .count();
+    if preceding % 2 == 1 {
+        return true;
+    }
+    ch == '\\' && text[idx + 1..].chars().next().is_some_and(needs_escape)
+}
+
+/// A `#` at a word start followed by a word character begins a hashtag.
+fn is_hashtag_start(text: &str, idx: usize) -> bool {
+    let at_word_start = text[..idx]
+        .chars()
+        .next_back()
+        .is_none_or(char::is_whitespace);
+    let followed_by_word = text[idx + 1..]
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_alphanumeric() || c == '_');
+    at_word_start && followed_by_word
+}
+
+/// Invisible characters that can reorder or hide text: control characters
+/// other than line breaks and tabs, bidi marks, embeddings, overrides and
+/// isolates, and zero-width spaces. The zero-width (non-)joiners stay, as
+/// emoji sequences and some scripts need them.
+fn is_invisible_control(ch: char) -> bool {
+    (ch.is_control() && !matches!(ch, '\n' | '\t' | '\r'))
+        || matches!(
+            ch,
+            '\u{061C}'
+                | '\u{200B}'
+                | '\u{200E}'
+                | '\u{200F}'
+                | '\u{202A}'..='\u{202E}'
+                | '\u{2060}'..='\u{2064}'
+                | '\u{2066}'..='\u{2069}'
+                | '\u{FEFF}'
+        )
+}
+
+/// Characters shown as emoji even without a variation selector.
+fn has_emoji_presentation(ch: char) -> bool {
+    matches!(
+        ch,
+        '\u{231A}'..='\u{231B}'
+            | '\u{23E9}'..='\u{23EC}'
+            | '\u{23F0}'
+            | '\u{23F3}'
+            | '\u{25FD}'..='\u{25FE}'
+            | '\u{2614}'..='\u{2615}'
+            | '\u{2648}'..='\u{2653}'
+            | '\u{267F}'
+            | '\u{2693}'
+            | '\u{26A1}'
+            | '\u{26AA}'..='\u{26AB}'
+            | '\u{26BD}'..='\u{26BE}'
+            | '\u{26C4}'..='\u{26C5}'
+            | '\u{26CE}'
+            | '\u{26D4}'
+            | '\u{26EA}'
+            | '\u{26F2}'..='\u{26F3}'
+            | '\u{26F5}'
+            | '\u{26FA}'
+            | '\u{26FD}'
+            | '\u{2705}'
+            | '\u{270A}'..='\u{270B}'
+            | '\u{2728}'
+            | '\u{274C}'
+            | '\u{274E}'
+            | '\u{2753}'..='\u{2755}'
+            | '\u{2757}'
+            | '\u{2795}'..='\u{2797}'
+            | '\u{27B0}'
+            | '\u{27BF}'
+            | '\u{2B1B}'..='\u{2B1C}'
+            | '\u{2B50}'
+            | '\u{2B55}'
+            | '\u{1F004}'
+            | '\u{1F0CF}'
+            | '\u{1F18E}'
+            | '\u{1F191}'..='\u{1F19A}'
+            | '\u{1F1E6}'..='\u{1F1FF}'
+            | '\u{1F201}'..='\u{1F251}'
+            | '\u{1F300}'..='\u{1F64F}'
+            | '\u{1F680}'..='\u{1F6FF}'
+            | '\u{1F7E0}'..='\u{1F7FF}'
+            | '\u{1F90C}'..='\u{1F9FF}'
+            | '\u{1FA70}'..='\u{1FAFF}'
+    )
+}
+
+/// Whether the character at byte `idx` of `text` belongs to an emoji: one
+/// shown as emoji by default, one made an emoji by a following U+FE0F or
+/// keycap, or a selector, skin tone, tag or joiner within an emoji.
+fn is_emoji_part(text: &str, idx: usize, ch: char) -> bool {
+    if has_emoji_presentation(ch)
+        || matches!(ch, '\u{FE0F}' | '\u{20E3}' | '\u{E0020}'..='\u{E007F}')
+    {
+        return true;
+    }
+    let after = &text[idx + ch.len_utf8()..];
+    if after.starts_with(['\u{FE0F}', '\u{20E3}']) {
+        return true;
+    }
+    ch == '\u{200D}'
+        && text[..idx]
+            .char_indices()
+            .next_back()
+            .is_some_and(|(prev, prev_ch)| is_emoji_part(text, prev, prev_ch))
+}
+
+/// Whether the space at byte `idx` goes along with a stripped emoji: one
+/// after an emoji that starts a word, so no double space is left behind, or
+/// one before emoji that end the line or the text.
+fn is_space_by_emoji(text: &str, idx: usize) -> bool {
+    let after = &text[idx + 1..];
+    let line = &after[..after.find('\n').unwrap_or(after.len())];
+    let emoji_end_line = line
+        .char_indices()
+        .all(|(at, ch)| ch == ' ' || is_emoji_part(after, at, ch))
+        && !line.is_empty()
+        && !line.ends_with(' ');
+    if emoji_end_line {
+        return true;
+    }
+
+    let leading = text[..idx]
+        .char_indices()
+        .rev()
+        .take_while(|&(at, ch)| is_emoji_part(text, at, ch))
+        .last();
+    leading.is_some_and(|(start, _)| {
+        text[..start]
+            .chars()
+            .next_back()
+            .is_none_or(char::is_whitespace)
+    })
+}
+
+/// Whether the character at byte `idx` of `text` is left out of the output
+/// altogether.
+fn is_stripped(
+    text: &str,
+    idx: usize,
+    ch: char,
+    context: EntityContext,
+    config: &ConverterConfig,
+) -> bool {
+    if config.strip_invisible_controls && is_invisible_control(ch) {
+        return true;
+    }
+    config.strip_emoji
+        && context.rules() == EntityContext::Text
+        && (is_emoji_part(text, idx, ch) || (ch == ' ' && is_space_by_emoji(text, idx)))
+}
+
+/// Escape `text` for `context` into the provided buffer.
+pub(crate) fn push_escaped_in(
+    out: &mut String,
+    text: &str,
+    context: EntityContext,
+    config: &ConverterConfig,
+) {
+    for (idx, ch) in text.char_indices() {
+        if is_stripped(text, idx, ch, context, config) {
+            continue;
+        }
+        if must_escape(text, idx, ch, context, config) {
+            out.push('\\');
+        }
+        out.push(ch);
+    }
+}
+
+pub(crate) fn escape_in(text: &str, context: EntityContext, config: &ConverterConfig) -> String {
+    let mut out = String::with_capacity(text.len());
+    push_escaped_in(&mut out, text, context, config);
+    out
+}
+
+/// Escape the content of inline code, a code block or its language.
+/// Telegram shows any other backslash there as written, so only `` ` `` and
+/// `\` are escaped, whatever the [`EscapeMode`].
+pub(crate) fn escape_code_content(text: &str, config: &ConverterConfig) -> String {
+    let mut out = String::with_capacity(text.len());
+    push_code_content(&mut out, text, config);
+    out
+}
+
+/// [`escape_code_content`] into the provided buffer.
+pub(crate) fn push_code_content(out: &mut String, text: &str, config: &ConverterConfig) {
+    push_escaped_in(out, text, EntityContext::Code, config);
+}
+
+/// Length in bytes of the prefix of `text` whose escaped form spans
+/// `escaped_len` bytes.
+pub(crate) fn unescaped_prefix_len(
+    text: &str,
+    escaped_len: usize,
+    context: EntityContext,
+    config: &ConverterConfig,
+) -> usize {
+    let mut escaped = 0;
+    for (idx, ch) in text.char_indices() {
+        if escaped >= escaped_len {
+            return idx;
+        }
+        if !is_stripped(text, idx, ch, context, config) {
+            escaped += ch.len_utf8() + usize::from(must_escape(text, idx, ch, context, config));
+        }
+    }
+    text.len()
+}
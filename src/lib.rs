@@ -1,13 +1,50 @@
 //! md2tgmdv2 — Markdown → Telegram MarkdownV2 renderer.
 //!
-//! Public entry point is [`transform`]. It renders Markdown into Telegram‑safe
-//! MarkdownV2 and splits the result into chunks that fit the provided limit.
+//! Public entry point is [`Converter`], or [`chunks`] for the defaults. It
+//! renders Markdown into Telegram‑safe MarkdownV2 and splits the result into
+//! chunks that fit the provided limit.
+
+mod error;
+mod escape;
+mod rendered;
+
+pub use error::Error;
+pub use escape::{EntityContext, escape_for};
+
+use std::borrow::Cow;
+use std::fmt;
+use std::panic::RefUnwindSafe;
+use std::sync::Arc;
 
 use anyhow::anyhow;
-use pulldown_cmark::{CodeBlockKind, Event, HeadingLevel, Options, Parser, Tag, TagEnd};
+use escape::{
+    escape_code_content, escape_in, push_code_content, push_escaped_in, unescaped_prefix_len,
+};
+use pulldown_cmark::{
+    BlockQuoteKind, CodeBlockKind, Event, HeadingLevel, LinkType, Options, Parser, Tag, TagEnd,
+};
+use rendered::{Marker, Token, count_entities, tokenize, validate};
 
 /// Telegram MarkdownV2 message hard limit.
 pub const TELEGRAM_BOT_MAX_MESSAGE_LENGTH: usize = 4096;
+/// Telegram limit for the caption of a photo, video or document.
+pub const TELEGRAM_BOT_MAX_CAPTION_LENGTH: usize = 1024;
+/// Smallest `max_len` accepted by [`Converter::set_max_len`]: room for the
+/// widest fixed markup (an H6 heading with [`HeadingStyle::Hashes`]) and its
+/// closer around one character.
+pub const MIN_MAX_LEN: usize = WIDEST_HEADING_PREFIX.len() + 2;
+/// Prefix of an H6 heading with [`HeadingStyle::Hashes`], the widest of all.
+const WIDEST_HEADING_PREFIX: &str = "_\\#\\#\\#\\#\\#\\# ";
+/// Marks a token cut short by [`OverflowStrategy::Truncate`].
+const ELLIPSIS: &str = "…";
+/// Default replacement for thematic breaks, which Telegram cannot render.
+const RULE: &str = "————————";
+/// Drawn under headings with [`HeadingStyle::Underlined`].
+const HEADING_UNDERLINE: &str = "─";
+/// Default marker of unordered list items.
+const BULLET: &str = "⦁";
+/// Invisible hyphen marking where a word may be broken.
+const SOFT_HYPHEN: char = '\u{ad}';
 const DEBUG_LOG: bool = false;
 
 macro_rules! debug_log {
@@ -18,23 +55,361 @@ macro_rules! debug_log {
     };
 }
 
-#[derive(Debug)]
+/// How Markdown tables are rendered once [`ConverterConfig::enable_tables`]
+/// is set. Telegram has no table entity, so both modes are approximations.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TableMode {
+    /// Each data row becomes a `*header*: value · *header*: value` line.
+    #[default]
+    KeyValue,
+    /// The table is drawn as a monospace grid inside a code block.
+    CodeBlock,
+}
+
+/// Telegram parse mode the output is written for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Dialect {
+    #[default]
+    MarkdownV2,
+    /// The legacy `Markdown` mode: bold, italic, code, code blocks and links
+    /// only, none of them nested, and just `_*`[` escaped. Strikethrough and
+    /// nested formatting are rendered as plain text, `<details>` as HTML text.
+    MarkdownV1,
+}
+
+/// Whitespace removed where a chunk ends and the next one begins. Code block
+/// content is never trimmed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChunkTrim {
+    /// Keep the whitespace a chunk was split at on the end of that chunk.
+    None,
+    /// Drop whitespace from the end of every chunk.
+    #[default]
+    Trailing,
+    /// Also drop whitespace from the start of every chunk, such as the
+    /// indent of a nested list item that continues there.
+    Both,
+}
+
+/// Marker that starts a heading, inside the bold (H1-H4) or italic (H5-H6)
+/// entity the heading is rendered as.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HeadingStyle {
+    /// One emoji per level, e.g. `*🌟 Title*`.
+    #[default]
+    Emoji,
+    /// The Markdown `#` count, e.g. `*\#\# Title*`.
+    Hashes,
+    /// No marker, but a line of `─` as long as the title under it.
+    Underlined,
+    /// No marker, e.g. `*Title*`.
+    Plain,
+}
+
+/// How aggressively link URLs are escaped. Prose and link labels always get
+/// the full MarkdownV2 escaping, and code only `` ` `` and `\`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EscapeMode {
+    /// Escape every MarkdownV2 control character in link URLs too.
+    #[default]
+    Conservative,
+    /// Escape only `)` and `\` in link URLs, as Telegram requires.
+    Minimal,
+}
+
+/// How chunk lengths are measured against `max_len`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LengthMetric {
+    /// UTF-8 bytes. Never smaller than the UTF-16 length, so always safe.
+    #[default]
+    Bytes,
+    /// UTF-16 code units, the unit Telegram counts its limits in. Characters
+    /// outside the BMP (most emoji, `𝕏`) count as two.
+    Utf16,
+}
+
+impl LengthMetric {
+    pub fn measure(self, text: &str) -> usize {
+        match self {
+            LengthMetric::Bytes => text.len(),
+            LengthMetric::Utf16 => utf16_len(text),
+        }
+    }
+}
+
+/// Length of `text` in UTF-16 code units; surrogate pairs count as two.
+pub fn utf16_len(text: &str) -> usize {
+    text.chars().map(char::len_utf16).sum()
+}
+
+/// What to do with a token that cannot be split (a word without whitespace
+/// or a link) and is longer than a whole chunk. This includes bare URLs,
+/// even when the URL is the whole message: pick `Error` if a sliced URL is
+/// worse than no message at all.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverflowStrategy {
+    /// Slice the token across chunks at a character boundary.
+    #[default]
+    HardSplit,
+    /// Keep what fits in one chunk, end it with `…` and drop the rest.
+    Truncate,
+    /// Fail with [`Error::ChunkTooSmall`].
+    Error,
+}
+
+/// Where chunks are allowed to end.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChunkStrategy {
+    /// Fill every chunk as far as `max_len` allows.
+    #[default]
+    Greedy,
+    /// Also start a new chunk at every top-level block (paragraph, heading,
+    /// list, quote, code block, table, thematic break). Blocks longer than
+    /// `max_len` are still split further.
+    PerBlock,
+}
+
+/// Callback that turns the URL of a link into the one written out, e.g. to
+/// add tracking parameters or route it through a proxy.
+#[derive(Clone)]
+pub struct UrlRewriter(Arc<dyn Fn(&str) -> String + Send + Sync + RefUnwindSafe>);
+
+impl UrlRewriter {
+    pub fn new(rewrite: impl Fn(&str) -> String + Send + Sync + RefUnwindSafe + 'static) -> Self {
+        Self(Arc::new(rewrite))
+    }
+
+    pub fn rewrite(&self, url: &str) -> String {
+        (self.0)(url)
+    }
+}
+
+impl fmt::Debug for UrlRewriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("UrlRewriter(..)")
+    }
+}
+
+/// A chunk returned by [`Converter::go_with_debug`], with the entities that
+/// were split across its boundaries. Entities are named like `Strong` or
+/// `Heading(H2)`, outermost first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DebugChunk {
+    pub text: String,
+    /// Entities an earlier chunk left open, reopened at the start.
+    pub open_at_start: Vec<String>,
+    /// Entities still open where the chunk was split, closed at the end.
+    pub open_at_end: Vec<String>,
+}
+
+/// Rendering options that survive between [`Converter::go`] calls.
+///
+/// The markers `chunk_continuation`, `bullet` and `quote_prefix` are written
+/// as given, without escaping, so they must already be valid MarkdownV2.
+/// Like `newline`, they count towards `max_len`.
+#[derive(Debug, Clone)]
+pub struct ConverterConfig {
+    /// Maximum length of a single chunk.
+    pub max_len: usize,
+    /// Parse GFM tables instead of leaving the pipes as plain text.
+    pub enable_tables: bool,
+    /// Table rendering used when `enable_tables` is set.
+    pub table_mode: TableMode,
+    /// Parse `- [ ]` and `- [x]` list items as tasks rendered with ☐/☑️.
+    /// When off, the default, the brackets stay plain text.
+    pub enable_task_lists: bool,
+    /// Parse `Term` lines followed by `: definition` as definition lists
+    /// with bold terms. When off, the default, the colon lines stay plain
+    /// text.
+    pub enable_definition_lists: bool,
+    /// Parse GitHub alerts such as `> [!NOTE]` and give them a title line.
+    /// When off, the default, they are ordinary quotes.
+    pub enable_alerts: bool,
+    /// Render links as `label (url)`, or just `url` when the label is the URL
+    /// itself, instead of clickable MarkdownV2 links.
+    pub links_as_text: bool,
+    /// Turn bare `http://`, `https://` and `www.` URLs in the text into links,
+    /// as GitHub does. `www.` links point to `http://`.
+    pub autolink_literals: bool,
+    /// Leave the `#` of hashtags like `#rust` unescaped so Telegram links them.
+    pub preserve_hashtags: bool,
+    /// Drop invisible control characters, such as the right-to-left override
+    /// (U+202E) and the zero-width space, that can disguise or reorder text.
+    pub strip_invisible_controls: bool,
+    /// Drop emoji from the text, along with the emoji that decorate
+    /// headings, alerts and checked tasks, for clients that render them
+    /// poorly. Code and link URLs are left as written.
+    pub strip_emoji: bool,
+    /// Keep valid MarkdownV2 escapes already present in the text (e.g.
+    /// machine-generated `\.`) instead of escaping their backslash again.
+    pub detect_pre_escaped: bool,
+    /// Which characters get escaped in link URLs.
+    pub escape_mode: EscapeMode,
+    /// Unit in which `max_len` and all chunk budgets are measured.
+    pub length_metric: LengthMetric,
+    /// Handling of tokens longer than `max_len`.
+    pub overflow: OverflowStrategy,
+    /// Markers around chunk boundaries, e.g. `(" ↩", "↪ ")`: the first ends
+    /// every chunk that continues in the next one, the second starts every
+    /// chunk after the first. Quote markers at the start of a chunk stay in
+    /// front of the second.
+    pub chunk_continuation: Option<(String, String)>,
+    /// Label of the link that stands in for an image. When empty or blank,
+    /// images are rendered as their bare URL instead.
+    pub image_fallback_label: String,
+    /// Leave images out of the text altogether, e.g. in the caption of the
+    /// media they would link to.
+    pub drop_images: bool,
+    /// Plain text that replaces thematic breaks (`---`, `***`, `___`, ...).
+    pub thematic_break: String,
+    /// How heading levels are marked.
+    pub heading_style: HeadingStyle,
+    /// Deepest heading level rendered as a heading. Deeper headings become
+    /// plain bold lines, without a marker or a place in the table of
+    /// contents.
+    pub max_heading_level: u8,
+    /// Start paragraphs and headings on the next line instead of leaving a
+    /// blank line before them.
+    pub compact_spacing: bool,
+    /// Parse a trailing `{#id .class}` block of a heading as attributes and
+    /// drop it, as Telegram has no anchors. Off by default since any trailing
+    /// `{...}`, like in `## Set {a, b}`, is then taken for attributes.
+    pub heading_attributes: bool,
+    /// Return at most this many chunks. Cut-off output ends with `…` placed
+    /// inside the entities that are still open, which are then closed.
+    pub max_chunks: Option<usize>,
+    /// Start a new chunk before it would hold more entities (formatting,
+    /// code, links, quotes) than this. Telegram rejects messages with too
+    /// many of them.
+    pub max_entities_per_chunk: Option<usize>,
+    /// Marker of unordered list items, e.g. `•`, followed by a space.
+    pub bullet: String,
+    /// Number nested ordered list items after their parents (`1.1`, `1.2`,
+    /// `2.1`) instead of restarting at 1 on every level.
+    pub legal_numbering: bool,
+    /// Whitespace trimmed at chunk boundaries.
+    pub chunk_trim: ChunkTrim,
+    /// Where chunks end besides where they are full.
+    pub chunk_strategy: ChunkStrategy,
+    /// Parse mode to render for. [`Converter::split_rendered`] and
+    /// [`Converter::go_validated`] always work with MarkdownV2.
+    pub dialect: Dialect,
+    /// Start the output with a list of the document's headings, indented by
+    /// level, in chunks of its own.
+    pub table_of_contents: bool,
+    /// Line break written into the chunks, e.g. `\r\n`. Counts towards
+    /// `max_len` like the rest of the text.
+    pub newline: String,
+    /// Marker repeated at the start of every quoted line, once per nesting
+    /// level. Anything but the default `>`, e.g. `▎`, renders quotes as
+    /// plain text.
+    pub quote_prefix: String,
+    /// Put a space between the quote markers and the quoted text, as in
+    /// `> text` instead of `>text`.
+    pub quote_space: bool,
+    /// Applied to the URL of every link and image before it is escaped.
+    pub url_rewriter: Option<UrlRewriter>,
+}
+
+impl Default for ConverterConfig {
+    fn default() -> Self {
+        Self {
+            max_len: TELEGRAM_BOT_MAX_MESSAGE_LENGTH,
+            enable_tables: false,
+            table_mode: TableMode::default(),
+            enable_task_lists: false,
+            enable_definition_lists: false,
+            enable_alerts: false,
+            links_as_text: false,
+            autolink_literals: false,
+            preserve_hashtags: false,
+            strip_invisible_controls: false,
+            strip_emoji: false,
+            detect_pre_escaped: false,
+            escape_mode: EscapeMode::default(),
+            length_metric: LengthMetric::default(),
+            overflow: OverflowStrategy::default(),
+            chunk_continuation: None,
+            image_fallback_label: "Image".to_string(),
+            drop_images: false,
+            thematic_break: RULE.to_string(),
+            heading_style: HeadingStyle::default(),
+            max_heading_level: 6,
+            compact_spacing: false,
+            heading_attributes: false,
+            max_chunks: None,
+            max_entities_per_chunk: None,
+            bullet: BULLET.to_string(),
+            legal_numbering: false,
+            chunk_trim: ChunkTrim::default(),
+            chunk_strategy: ChunkStrategy::default(),
+            dialect: Dialect::default(),
+            table_of_contents: false,
+            newline: "\n".to_string(),
+            quote_prefix: ">".to_string(),
+            quote_space: false,
+            url_rewriter: None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
 pub struct Converter {
-    max_len: usize,
+    config: ConverterConfig,
     result: Vec<String>,
     stack: Vec<Descriptor>,
     add_new_line: bool,
     after_heading: bool,
-    quote_level: u8,
+    quote_level: usize,
     list_stack: Vec<ListState>,
     carry_list_indent_levels: usize,
     after_list_prefix: bool,
     last_list_prefix: String,
     list_body_written: bool,
     heading_body_written: bool,
+    // Characters of text in the open heading, for its underline.
+    heading_width: usize,
+    // Set while an HTML comment continues into the next HTML event.
+    in_html_comment: bool,
     link_dest_url: String,
+    // Label of the link being rendered, collected until the link ends.
+    link_label: Option<LinkLabel>,
     // Depth counter for temporarily skipping events (used for image alt text).
     skip_depth: u16,
+    // Set while writing an opening fence, whose line must not be indented.
+    opening_code_block: bool,
+    // The next quote prefix opens an expandable quote (`**>`).
+    expandable_quote_start: bool,
+    // A `<details>` quote with no line written yet; its descriptor is pushed
+    // with the first one, so a split before it closes nothing.
+    expandable_quote_pending: bool,
+    // Cells collected while inside a table; rendered on the table end tag.
+    table: Option<TableState>,
+    // Stop rendering once this many chunks are complete.
+    chunk_limit: Option<usize>,
+    // Set when rendering stopped at `chunk_limit` with content left over.
+    truncated: bool,
+    // End the last chunk allowed by `chunk_limit` with an ellipsis.
+    ellipsis_on_split: bool,
+    // Length of the first token that overflowed under `OverflowStrategy::Error`.
+    overflow_len: Option<usize>,
+    // Byte offset in the source markdown of the content currently being written.
+    source_pos: usize,
+    // Source offsets at which each chunk after the first begins.
+    split_points: Vec<usize>,
+    // For every split, the entities closed before it and reopened after it.
+    split_stacks: Vec<(Vec<Descriptor>, Vec<Descriptor>)>,
+    // For every returned chunk, the entities open at its start and its end.
+    chunk_bounds: Vec<(Vec<Descriptor>, Vec<Descriptor>)>,
+    // Room left in the last chunk of the previous conversion.
+    last_chunk_remaining: usize,
+    // Length of the current chunk's reopened entities and carried prefixes.
+    // Splitting a chunk no longer than this can't make room for anything.
+    chunk_lead_len: usize,
+    // Entities opened or reopened in the current chunk.
+    chunk_entities: usize,
+    // Chunks of the `go_append` calls so far.
+    appended: Vec<String>,
 }
 
 /// Small helper used to budget space in the current chunk before emitting new
@@ -79,50 +454,511 @@ enum Descriptor {
     Strikethrough,
     Code,
     Heading(HeadingLevel),
-}
-
-impl Default for Converter {
-    fn default() -> Self {
-        Self {
-            max_len: TELEGRAM_BOT_MAX_MESSAGE_LENGTH,
-            result: vec![],
-            stack: Vec::new(),
-            add_new_line: false,
-            after_heading: false,
-            quote_level: 0,
-            list_stack: Vec::new(),
-            carry_list_indent_levels: 0,
-            after_list_prefix: false,
-            last_list_prefix: String::new(),
-            list_body_written: false,
-            heading_body_written: false,
-            link_dest_url: String::new(),
-            skip_depth: 0,
-        }
-    }
+    Underline,
+    Spoiler,
+    // An expandable blockquote rendered from `<details>`.
+    ExpandableQuote,
+    // Formatting the dialect can't express; its text is written unmarked.
+    Plain,
 }
 
 impl Converter {
     pub fn new(max_len: usize) -> Self {
-        Self {
+        Self::with_config(ConverterConfig {
             max_len,
             ..Default::default()
+        })
+    }
+
+    /// Converter for the caption of a photo or other media: chunks of the
+    /// caption limit and no images, as the media is already attached.
+    pub fn for_caption() -> Self {
+        Self::with_config(ConverterConfig {
+            max_len: TELEGRAM_BOT_MAX_CAPTION_LENGTH,
+            drop_images: true,
+            ..Default::default()
+        })
+    }
+
+    /// Converter for clean, minimal output: headings without emoji, no blank
+    /// lines between blocks and `•` bullets.
+    pub fn compact() -> Self {
+        Self::with_config(ConverterConfig {
+            heading_style: HeadingStyle::Plain,
+            compact_spacing: true,
+            bullet: "•".to_string(),
+            ..Default::default()
+        })
+    }
+
+    pub fn with_config(config: ConverterConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// Chunk limit used by [`Converter::go`], as set at construction or by
+    /// [`Converter::set_max_len`].
+    pub fn max_len(&self) -> usize {
+        self.config.max_len
+    }
+
+    /// Change the chunk limit used by subsequent [`Converter::go`] calls.
+    pub fn set_max_len(&mut self, max_len: usize) -> anyhow::Result<()> {
+        if max_len < MIN_MAX_LEN {
+            return Err(anyhow!(
+                "max_len {} is below the minimum of {}",
+                max_len,
+                MIN_MAX_LEN
+            ));
         }
+        self.config.max_len = max_len;
+
+        Ok(())
+    }
+
+    /// Check that the configuration can render any document, so a bad
+    /// setting fails here with the option to change rather than deep inside
+    /// [`Converter::go`].
+    pub fn validate_config(&self) -> anyhow::Result<()> {
+        let config = &self.config;
+        if config.max_len < MIN_MAX_LEN {
+            return Err(anyhow!(
+                "max_len {} is below the minimum of {}",
+                config.max_len,
+                MIN_MAX_LEN
+            ));
+        }
+
+        if let Some((suffix, prefix)) = &config.chunk_continuation {
+            let reserved = self.measure(suffix) + self.measure(prefix);
+            if config.max_len.saturating_sub(reserved) < MIN_MAX_LEN {
+                return Err(anyhow!(
+                    "chunk_continuation markers take {} of max_len {}, leaving less than \
+                     the minimum of {}; shorten them or raise max_len",
+                    reserved,
+                    config.max_len,
+                    MIN_MAX_LEN
+                ));
+            }
+        }
+
+        if config.max_chunks == Some(0) {
+            return Err(anyhow!(
+                "max_chunks is Some(0), so nothing would be rendered; use None for no limit"
+            ));
+        }
+        if config.max_entities_per_chunk == Some(0) {
+            return Err(anyhow!(
+                "max_entities_per_chunk is Some(0), so no formatting could be rendered; \
+                 use None for no limit"
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Drop all per-document state while keeping the configuration. Buffers
+    /// are cleared in place, so a reused converter keeps their capacity.
+    fn reset(&mut self) {
+        // Destructured so that a new field can't be left out.
+        let Self {
+            config: _,
+            result,
+            stack,
+            add_new_line,
+            after_heading,
+            quote_level,
+            list_stack,
+            carry_list_indent_levels,
+            after_list_prefix,
+            last_list_prefix,
+            list_body_written,
+            heading_body_written,
+            heading_width,
+            in_html_comment,
+            link_dest_url,
+            link_label,
+            skip_depth,
+            opening_code_block,
+            expandable_quote_start,
+            expandable_quote_pending,
+            table,
+            chunk_limit,
+            truncated,
+            ellipsis_on_split,
+            overflow_len,
+            source_pos,
+            split_points,
+            split_stacks,
+            chunk_bounds,
+            last_chunk_remaining,
+            chunk_lead_len,
+            chunk_entities,
+            appended,
+        } = self;
+        result.clear();
+        stack.clear();
+        *add_new_line = false;
+        *after_heading = false;
+        *quote_level = 0;
+        list_stack.clear();
+        *carry_list_indent_levels = 0;
+        *after_list_prefix = false;
+        last_list_prefix.clear();
+        *list_body_written = false;
+        *heading_body_written = false;
+        *heading_width = 0;
+        *in_html_comment = false;
+        link_dest_url.clear();
+        *link_label = None;
+        *skip_depth = 0;
+        *opening_code_block = false;
+        *expandable_quote_start = false;
+        *expandable_quote_pending = false;
+        *table = None;
+        *chunk_limit = None;
+        *truncated = false;
+        *ellipsis_on_split = false;
+        *overflow_len = None;
+        *source_pos = 0;
+        split_points.clear();
+        split_stacks.clear();
+        chunk_bounds.clear();
+        *last_chunk_remaining = 0;
+        *chunk_lead_len = 0;
+        *chunk_entities = 0;
+        appended.clear();
     }
 
     /// Convert Markdown into Telegram MarkdownV2 and split into safe chunks.
+    ///
+    /// Never panics, whatever the input: anything the converter can't
+    /// render within the configured limits comes back as an error.
     pub fn go(&mut self, markdown: &str) -> anyhow::Result<Vec<String>> {
-        *self = Self::new(self.max_len);
+        let mut chunks = Vec::new();
+        let mut toc_bounds = Vec::new();
+        if self.config.table_of_contents
+            && let Some(toc) = self.table_of_contents(markdown)
+        {
+            self.reset();
+            chunks = self.with_continuation(|converter| converter.run(&toc))?;
+            toc_bounds = std::mem::take(&mut self.chunk_bounds);
+        }
+        self.reset();
+        chunks.extend(match self.config.max_chunks {
+            Some(limit) => {
+                self.with_continuation(|converter| converter.run_limited(markdown, limit))
+            }
+            None => self.with_continuation(|converter| converter.run(markdown)),
+        }?);
+        self.chunk_bounds.splice(0..0, toc_bounds);
 
+        Ok(chunks)
+    }
+
+    /// Render `markdown` after everything earlier `go_append` calls rendered,
+    /// e.g. the next message of a conversation, and return all chunks so far.
+    /// It starts a new paragraph in the last chunk if there is room left.
+    /// [`Converter::go`] and the other conversions start over.
+    pub fn go_append(&mut self, markdown: &str) -> anyhow::Result<Vec<String>> {
+        let earlier = std::mem::take(&mut self.appended);
+        self.reset();
+        let mut chunks = earlier.clone();
+        if !markdown.trim().is_empty() {
+            if let Some(last) = chunks.pop() {
+                self.result.push(self.plain_newlines(&last).into_owned());
+                self.add_new_line = true;
+            }
+            match self.with_continuation(|converter| converter.run(markdown)) {
+                Ok(rendered) => chunks.extend(rendered),
+                Err(err) => {
+                    self.appended = earlier;
+                    return Err(err);
+                }
+            }
+        }
+        self.appended.clone_from(&chunks);
+
+        Ok(chunks)
+    }
+
+    /// Like [`Converter::go`], but also report for every chunk which
+    /// entities were split off at its start and end, to see why the output
+    /// was split where it was.
+    pub fn go_with_debug(&mut self, markdown: &str) -> anyhow::Result<Vec<DebugChunk>> {
+        let chunks = self.go(markdown)?;
+        Ok(chunks
+            .into_iter()
+            .zip(&self.chunk_bounds)
+            .map(|(text, (start, end))| DebugChunk {
+                text,
+                open_at_start: entity_names(start),
+                open_at_end: entity_names(end),
+            })
+            .collect())
+    }
+
+    /// Markdown list of the headings in `markdown`, nested by level, or
+    /// `None` when there are no headings.
+    fn table_of_contents(&self, markdown: &str) -> Option<String> {
+        let mut headings: Vec<(HeadingLevel, String)> = Vec::new();
+        let mut title: Option<String> = None;
+        for event in Parser::new_ext(markdown, self.parser_options()) {
+            match event {
+                Event::Start(Tag::Heading { .. }) => title = Some(String::new()),
+                Event::Text(text) | Event::Code(text) => {
+                    if let Some(title) = title.as_mut() {
+                        title.push_str(&text);
+                    }
+                }
+                Event::End(TagEnd::Heading(level)) => {
+                    let title = title.take().unwrap_or_default();
+                    if !is_flattened(level, &self.config) {
+                        headings.push((level, title));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let top = headings.iter().map(|(level, _)| *level).min()?;
+        let mut toc = String::new();
+        let mut depth = 0;
+        for (level, title) in headings {
+            // A list can only nest one level deeper than its parent item.
+            depth = (level as usize - top as usize).min(depth + 1);
+            toc.push_str(&"  ".repeat(depth));
+            toc.push_str("- ");
+            // Escape what would format the title or, at its start, nest
+            // another list; escaped text splits into separate text events.
+            let title = title.trim();
+            let number_len =
+                title.len() - title.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+            for (idx, ch) in title.char_indices() {
+                let leading = idx == 0 || (idx == number_len && matches!(ch, '.' | ')'));
+                if "\\`*_[]<>!|~&".contains(ch) || (leading && ch.is_ascii_punctuation()) {
+                    toc.push('\\');
+                }
+                toc.push(ch);
+            }
+            toc.push('\n');
+        }
+        Some(toc)
+    }
+
+    /// Like [`Converter::go`], but also check every chunk the way Telegram
+    /// parses it, so output it would reject comes back as an error instead.
+    pub fn go_validated(&mut self, markdown: &str) -> anyhow::Result<Vec<String>> {
+        let chunks = self.go(markdown)?;
+        for (idx, chunk) in chunks.iter().enumerate() {
+            validate(&self.plain_newlines(chunk), self.config.preserve_hashtags)
+                .map_err(|err| anyhow!("chunk {idx} would be rejected by Telegram: {err}"))?;
+        }
+
+        Ok(chunks)
+    }
+
+    /// Number of MarkdownV2 entities (bold, italic, code, links, quotes, ...)
+    /// in the chunks [`Converter::go`] returns for `markdown`. An entity
+    /// split across chunks counts once in each of them, as each chunk is a
+    /// message of its own.
+    pub fn entity_count(&mut self, markdown: &str) -> anyhow::Result<usize> {
+        let chunks = self.go(markdown)?;
+        chunks
+            .iter()
+            .map(|chunk| count_entities(&self.plain_newlines(chunk)))
+            .sum()
+    }
+
+    /// `chunk` with the configured newline turned back into `\n`.
+    fn plain_newlines<'a>(&self, chunk: &'a str) -> Cow<'a, str> {
+        match self.config.newline.as_str() {
+            "" | "\n" => Cow::Borrowed(chunk),
+            newline => Cow::Owned(chunk.replace(newline, "\n")),
+        }
+    }
+
+    /// Convert only as much of `markdown` as the first `n` chunks need; they
+    /// are the same as the first `n` chunks of [`Converter::go`]. Unlike
+    /// `max_chunks`, nothing marks where the output was cut off.
+    pub fn go_take(&mut self, markdown: &str, n: usize) -> anyhow::Result<Vec<String>> {
+        self.reset();
+        self.with_continuation(|converter| {
+            converter.chunk_limit = Some(n);
+            converter.run(markdown)
+        })
+    }
+
+    /// Convert several independent documents, returning the chunks of each.
+    /// State is reset between documents, so nothing opened in one of them
+    /// carries over into the next.
+    pub fn go_multi(&mut self, docs: &[&str]) -> anyhow::Result<Vec<Vec<String>>> {
+        docs.iter().map(|markdown| self.go(markdown)).collect()
+    }
+
+    /// Convert each part of `markdown` between top-level thematic breaks
+    /// (`---`, `***`, ...) separately, returning the chunks of each section.
+    /// Setext headings (`Title` underlined with `---`) are not breaks, and
+    /// reference link definitions only apply within their own section.
+    pub fn go_sections(&mut self, markdown: &str) -> anyhow::Result<Vec<Vec<String>>> {
+        let mut sections = Vec::new();
+        let mut section_start = 0;
+        let mut depth = 0usize;
+        let parser = Parser::new_ext(markdown, self.parser_options()).into_offset_iter();
+        for (event, range) in parser {
+            match event {
+                Event::Start(_) => depth += 1,
+                Event::End(_) => depth -= 1,
+                Event::Rule if depth == 0 => {
+                    sections.push(&markdown[section_start..range.start]);
+                    section_start = range.end;
+                }
+                _ => {}
+            }
+        }
+        sections.push(&markdown[section_start..]);
+        sections.retain(|section| !section.trim().is_empty());
+
+        self.go_multi(&sections)
+    }
+
+    /// Render only the beginning of `markdown` as a single message of at most
+    /// `max_chars` (measured like `max_len`). Cut-off content is marked with a
+    /// trailing `…` placed inside any entities that are still open, which are
+    /// then closed.
+    pub fn go_preview(&mut self, markdown: &str, max_chars: usize) -> anyhow::Result<String> {
+        let max_len = self.config.max_len;
+        let preview = self.preview(markdown, max_chars);
+        self.config.max_len = max_len;
+        preview
+    }
+
+    /// Render with `max_len` lowered by the continuation markers, then add
+    /// them at the chunk boundaries.
+    fn with_continuation(
+        &mut self,
+        render: impl FnOnce(&mut Self) -> anyhow::Result<Vec<String>>,
+    ) -> anyhow::Result<Vec<String>> {
+        let Some((suffix, prefix)) = self.config.chunk_continuation.clone() else {
+            return render(self);
+        };
+        let max_len = self.config.max_len;
+        let reserved = self.measure(&suffix) + self.measure(&prefix);
+        self.config.max_len = max_len.saturating_sub(reserved);
+        let chunks = render(self);
+        self.config.max_len = max_len;
+
+        let mut chunks = chunks?;
+        // Chunks cut off without an ellipsis still continue in the ones that
+        // weren't rendered.
+        let last = if self.truncated && !self.ellipsis_on_split {
+            chunks.len()
+        } else {
+            chunks.len().saturating_sub(1)
+        };
+        // The chunks were rendered with the markers' room held back.
+        self.last_chunk_remaining += reserved;
+        let count = chunks.len();
+        for (idx, chunk) in chunks.iter_mut().enumerate() {
+            if idx + 1 == count {
+                let added = if idx > 0 { self.measure(&prefix) } else { 0 }
+                    + if idx < last { self.measure(&suffix) } else { 0 };
+                self.last_chunk_remaining = self.last_chunk_remaining.saturating_sub(added);
+            }
+            if idx > 0 {
+                // Keep the quote markers, and the `**` that opens an
+                // expandable quote before them, at the start of the line.
+                let opener_len = if chunk.starts_with("**>") { 2 } else { 0 };
+                let mut quote_len = chunk.len()
+                    - chunk[opener_len..]
+                        .trim_start_matches(self.config.quote_prefix.as_str())
+                        .len();
+                if quote_len > opener_len
+                    && self.config.quote_space
+                    && chunk[quote_len..].starts_with(' ')
+                {
+                    quote_len += 1;
+                }
+                chunk.insert_str(quote_len, &prefix);
+            }
+            if idx < last {
+                // The `||` that closes an expandable quote must end its line.
+                let ends_quote = tokenize(chunk)
+                    .is_ok_and(|tokens| tokens.last() == Some(&Token::ExpandableQuoteEnd));
+                let end = chunk.len() - if ends_quote { 2 } else { 0 };
+                chunk.insert_str(end, &suffix);
+            }
+        }
+        Ok(chunks)
+    }
+
+    fn preview(&mut self, markdown: &str, max_chars: usize) -> anyhow::Result<String> {
+        self.config.max_len = max_chars;
+        self.reset();
+        let chunks = self.run_limited(markdown, 1)?;
+        Ok(chunks.into_iter().next().unwrap_or_default())
+    }
+
+    /// Render at most `limit` chunks. When content is cut off, the last chunk
+    /// ends with `…` inside any entities that are still open.
+    fn run_limited(&mut self, markdown: &str, limit: usize) -> anyhow::Result<Vec<String>> {
+        self.chunk_limit = Some(limit);
+        let chunks = self.run(markdown)?;
+        if !self.truncated {
+            return Ok(chunks);
+        }
+
+        // Render again, leaving room for the ellipsis in front of the closers.
+        self.reset();
+        self.chunk_limit = Some(limit);
+        self.ellipsis_on_split = true;
+        self.run(markdown)
+    }
+
+    fn run(&mut self, markdown: &str) -> anyhow::Result<Vec<String>> {
+        let source_lead = markdown.len() - markdown.trim_start().len();
         let markdown = markdown.trim();
         if markdown.is_empty() {
             return Ok(vec![]);
         }
 
-        self.result.push(String::new());
+        if self.result.is_empty() {
+            self.result.push(String::new());
+        }
 
-        let parser = Parser::new_ext(markdown, Options::ENABLE_STRIKETHROUGH);
-        for event in parser {
+        let mut parser = Parser::new_ext(markdown, self.parser_options())
+            .into_offset_iter()
+            .peekable();
+        let mut depth = 0usize;
+        while let Some((event, range)) = parser.next() {
+            if self
+                .chunk_limit
+                .is_some_and(|limit| self.result.len() > limit)
+            {
+                break;
+            }
+            self.source_pos = source_lead + range.start;
+            if self.config.chunk_strategy == ChunkStrategy::PerBlock
+                && depth == 0
+                && matches!(event, Event::Start(_) | Event::Rule)
+                // An expandable quote spans several top-level blocks.
+                && self.stack.is_empty()
+                && self.current_len() > 0
+            {
+                self.split_chunk();
+            }
+            match event {
+                Event::Start(_) => depth += 1,
+                Event::End(_) => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+            if let Some(table) = self.table.as_mut()
+                && table.capture(&event)
+            {
+                continue;
+            }
             if self.skip_depth > 0 {
                 // When skipping (e.g., image alt text), keep depth balanced.
                 match &event {
@@ -132,6 +968,26 @@ impl Converter {
                 }
                 continue;
             }
+            if let Some(label) = self.link_label.as_mut() {
+                match &event {
+                    Event::Text(txt) => label.push_text(txt, &self.config),
+                    Event::Code(txt) => label.push_code(txt, &self.config),
+                    Event::SoftBreak | Event::HardBreak => label.push_text(" ", &self.config),
+                    _ => {}
+                }
+                // Formatting inside the label is flattened to its text.
+                if matches!(
+                    event,
+                    Event::Text(_)
+                        | Event::Code(_)
+                        | Event::SoftBreak
+                        | Event::HardBreak
+                        | Event::Start(Tag::Strong | Tag::Emphasis | Tag::Strikethrough)
+                        | Event::End(TagEnd::Strong | TagEnd::Emphasis | TagEnd::Strikethrough)
+                ) {
+                    continue;
+                }
+            }
             match event {
                 Event::Start(tag) => {
                     self.start_tag(tag)?;
@@ -140,23 +996,22 @@ impl Converter {
                     self.end_tag(tag)?;
                 }
                 Event::Text(txt) => {
-                    if self.link_dest_url.is_empty() {
-                        self.output(&txt, true);
+                    self.count_heading_width(&txt);
+                    if self.config.autolink_literals && !self.in_code_block() {
+                        self.write_autolinked(&txt);
                     } else {
-                        let mut link = String::new();
-                        link.push('[');
-                        push_escaped(&mut link, &txt);
-                        link.push_str("](");
-                        push_escaped(&mut link, &self.link_dest_url);
-                        link.push(')');
-                        self.write(&link, false, false, false);
-
-                        self.link_dest_url.clear();
+                        self.output(&txt, true);
                     }
 
                     debug_log!("Text {}", txt);
                 }
+                Event::Code(txt) if self.is_plain(&Descriptor::Code) => {
+                    self.count_heading_width(&txt);
+                    self.output(&txt, true);
+                }
                 Event::Code(txt) => {
+                    self.count_heading_width(&txt);
+                    self.count_entity();
                     self.ensure_space(SpaceBudget::for_open(1, 1, 1));
                     self.stack.push(Descriptor::Code);
                     self.output("`", false);
@@ -177,12 +1032,47 @@ impl Converter {
                     debug_log!("DisplayMath");
                 }
                 Event::Html(txt) => {
-                    self.output(&txt, true);
+                    let html = without_html_comments(&txt, &mut self.in_html_comment);
+                    if html.trim().is_empty() {
+                        // Nothing but comments.
+                    } else if self.config.dialect == Dialect::MarkdownV2
+                        && let Some(tags) = details_tags(&html)
+                    {
+                        for tag in tags {
+                            self.write_details_tag(tag)?;
+                        }
+                    } else {
+                        // Lines go through the line prefix, so quoted HTML
+                        // stays in its quote.
+                        for (idx, line) in html.split('\n').enumerate() {
+                            if idx > 0 {
+                                self.add_new_line = true;
+                            }
+                            if !line.is_empty() {
+                                self.output(line, true);
+                            }
+                        }
+                    }
 
                     debug_log!("Html");
                 }
+                Event::InlineHtml(txt) if is_line_break_tag(&txt) => {
+                    self.add_new_line = true;
+
+                    debug_log!("InlineHtml <br>");
+                }
                 Event::InlineHtml(txt) => {
-                    self.output(&txt, true);
+                    let html = without_html_comments(&txt, &mut self.in_html_comment);
+                    // A dropped comment between two spaces leaves only one.
+                    if html.is_empty()
+                        && self.result.last().is_some_and(|chunk| chunk.ends_with(' '))
+                        && let Some((Event::Text(next), next_range)) = parser.peek_mut()
+                    {
+                        let trimmed = next.trim_start_matches(' ');
+                        next_range.start += next.len() - trimmed.len();
+                        *next = trimmed.to_string().into();
+                    }
+                    self.output(&html, true);
 
                     debug_log!("InlineHtml");
                 }
@@ -202,18 +1092,34 @@ impl Converter {
                     debug_log!("HardBreak");
                 }
                 Event::Rule => {
-                    self.new_line();
-                    self.output("————————", true);
+                    // A rule before all content or after it separates nothing.
+                    let at_start = self.result.len() == 1 && self.current_len() == 0;
+                    if at_start || parser.peek().is_none() {
+                        continue;
+                    }
+                    // Move a rule that doesn't fit to the next chunk before
+                    // emitting its newline, so neither chunk gets a blank edge.
+                    let rule = escape_in(
+                        &self.config.thematic_break,
+                        EntityContext::Text,
+                        &self.config,
+                    );
+                    let newline_len = self.measure("\n") + self.quote_markers_len();
+                    self.ensure_space(SpaceBudget::for_prefix(newline_len, self.measure(&rule)));
+                    self.block_gap();
+                    self.write(&rule, false, false, false);
                     self.add_new_line = true;
 
                     debug_log!("Rule");
                 }
                 Event::TaskListMarker(b) => {
-                    self.new_line();
-                    if b {
-                        self.output("☑️", false);
+                    // The checkbox follows the list marker on the same line.
+                    if b && self.config.strip_emoji {
+                        self.output("☒ ", false);
+                    } else if b {
+                        self.output("☑️ ", false);
                     } else {
-                        self.output("☐", false);
+                        self.output("☐ ", false);
                     }
 
                     debug_log!("TaskListMarker({})", b);
@@ -221,24 +1127,299 @@ impl Converter {
             }
         }
 
-        if !self.stack.is_empty() {
+        // Close expandable quotes whose `</details>` never came.
+        while self.expandable_quote_pending
+            || self.stack.last() == Some(&Descriptor::ExpandableQuote)
+        {
+            self.write_details_tag(DetailsTag::Close)?;
+        }
+
+        self.finish()
+    }
+
+    /// Render `<details>` as an expandable blockquote whose first line is
+    /// the bold `<summary>`.
+    fn write_details_tag(&mut self, tag: DetailsTag) -> anyhow::Result<()> {
+        match tag {
+            DetailsTag::Open => {
+                self.start_tag(Tag::BlockQuote(None))?;
+                self.expandable_quote_start = true;
+                self.expandable_quote_pending = true;
+            }
+            DetailsTag::Summary(summary) => {
+                self.new_line();
+                self.count_entity();
+                self.ensure_space(SpaceBudget::for_open(1, 1, 1));
+                self.output("*", false);
+                self.stack.push(Descriptor::Strong);
+                self.output(summary, true);
+                self.output_closing("*", false);
+                self.close_descriptor(Descriptor::Strong)?;
+            }
+            // An empty `<details>` left nothing to close but the quote.
+            DetailsTag::Close if self.expandable_quote_pending => {
+                self.expandable_quote_pending = false;
+                self.expandable_quote_start = false;
+                self.end_tag(TagEnd::BlockQuote(None))?;
+            }
+            // A stray `</details>` with no `<details>` to close.
+            DetailsTag::Close if self.stack.last() != Some(&Descriptor::ExpandableQuote) => {}
+            DetailsTag::Close => {
+                // The expandability mark ends the last line of the quote.
+                self.add_new_line = false;
+                self.expandable_quote_start = false;
+                self.output_closing("||", false);
+                self.close_descriptor(Descriptor::ExpandableQuote)?;
+                self.end_tag(TagEnd::BlockQuote(None))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn parser_options(&self) -> Options {
+        let mut options = Options::ENABLE_STRIKETHROUGH;
+        if self.config.enable_task_lists {
+            options |= Options::ENABLE_TASKLISTS;
+        }
+        if self.config.enable_definition_lists {
+            options |= Options::ENABLE_DEFINITION_LIST;
+        }
+        if self.config.enable_alerts {
+            options |= Options::ENABLE_GFM;
+        }
+        if self.config.enable_tables {
+            options |= Options::ENABLE_TABLES;
+        }
+        if self.config.heading_attributes {
+            options |= Options::ENABLE_HEADING_ATTRIBUTES;
+        }
+        options
+    }
+
+    /// Validate the rendered chunks and hand them out.
+    fn finish(&mut self) -> anyhow::Result<Vec<String>> {
+        if let Some(limit) = self.chunk_limit
+            && self.result.len() > limit
+        {
+            self.result.truncate(limit);
+            self.truncated = true;
+        }
+
+        if !self.stack.is_empty() && !self.truncated {
             return Err(anyhow!("Unbalanced tags"));
         }
 
+        if let Some(needed) = self.overflow_len {
+            return Err(Error::ChunkTooSmall {
+                needed,
+                max_len: self.config.max_len,
+            }
+            .into());
+        }
+
+        self.drop_empty_chunks();
+        if self.config.chunk_trim == ChunkTrim::Both {
+            // Chunks inside a code block start with its reopened fence.
+            for chunk in &mut self.result {
+                let lead = chunk.len() - chunk.trim_start_matches([' ', '\t']).len();
+                chunk.drain(..lead);
+            }
+        }
         for (idx, chunk) in self.result.iter().enumerate() {
-            if chunk.len() > self.max_len {
+            let len = self.measure(chunk);
+            if len > self.config.max_len {
                 return Err(anyhow!(
                     "internal parser error: chunk {} exceeds max_len ({} > {})",
                     idx,
-                    chunk.len(),
-                    self.max_len
+                    len,
+                    self.config.max_len
                 ));
             }
         }
 
+        let last_len = self.result.last().map_or(0, |chunk| self.measure(chunk));
+        self.last_chunk_remaining = self.config.max_len.saturating_sub(last_len);
+
+        if self.config.newline != "\n" {
+            for chunk in &mut self.result {
+                *chunk = chunk.replace('\n', &self.config.newline);
+            }
+        }
         Ok(std::mem::take(&mut self.result))
     }
 
+    /// Remove chunks with nothing but whitespace, which Telegram rejects,
+    /// along with the split points at which they began.
+    fn drop_empty_chunks(&mut self) {
+        let chunks = std::mem::take(&mut self.result);
+        let mut split_points = Vec::with_capacity(self.split_points.len());
+        self.chunk_bounds.clear();
+        for (idx, chunk) in chunks.into_iter().enumerate() {
+            if chunk.trim().is_empty() {
+                continue;
+            }
+            if !self.result.is_empty()
+                && let Some(&point) = self.split_points.get(idx - 1)
+            {
+                split_points.push(point);
+            }
+            let start = idx
+                .checked_sub(1)
+                .and_then(|split| self.split_stacks.get(split))
+                .map_or_else(Vec::new, |(_, reopened)| reopened.clone());
+            let end = self
+                .split_stacks
+                .get(idx)
+                .map_or_else(Vec::new, |(closed, _)| closed.clone());
+            self.chunk_bounds.push((start, end));
+            self.result.push(chunk);
+        }
+        self.split_points = split_points;
+    }
+
+    /// Split text that is already Telegram MarkdownV2 into chunks, keeping
+    /// its entities balanced across chunk boundaries. Nothing is escaped
+    /// again, so the input must already be valid MarkdownV2.
+    pub fn split_rendered(&mut self, tg_markdown: &str) -> anyhow::Result<Vec<String>> {
+        self.reset();
+        self.with_continuation(|converter| converter.run_rendered(tg_markdown))
+    }
+
+    fn run_rendered(&mut self, tg_markdown: &str) -> anyhow::Result<Vec<String>> {
+        let tg_markdown = tg_markdown.trim();
+        if tg_markdown.is_empty() {
+            return Ok(vec![]);
+        }
+
+        self.result.push(String::new());
+        let mut line_start = true;
+        for token in tokenize(tg_markdown)? {
+            // A line that doesn't start with `>` is outside any quote.
+            if line_start && !matches!(token, Token::Text(_) | Token::ExpandableQuote) {
+                self.quote_level = 0;
+            }
+            match token {
+                Token::Text(text) => {
+                    for (idx, line) in text.split('\n').enumerate() {
+                        if idx > 0 && self.current_len() > 0 {
+                            // A second pending newline is a blank line.
+                            if self.add_new_line {
+                                self.flush_pending_prefix();
+                            }
+                            self.add_new_line = true;
+                        }
+                        let mut line = line;
+                        if idx > 0 || line_start {
+                            // Quote markers are written by the line prefix,
+                            // so lines continued in a new chunk keep them.
+                            let body = line.trim_start_matches('>');
+                            self.quote_level = line.len() - body.len();
+                            line = body;
+                        }
+                        self.output(line, false);
+                    }
+                    line_start = text.ends_with('\n') || (line_start && text.is_empty());
+                    continue;
+                }
+                Token::ExpandableQuote => {
+                    self.expandable_quote_start = true;
+                    self.expandable_quote_pending = true;
+                    continue;
+                }
+                Token::ExpandableQuoteEnd => {
+                    if self.expandable_quote_pending {
+                        self.expandable_quote_pending = false;
+                        self.expandable_quote_start = false;
+                    } else if self.stack.last() == Some(&Descriptor::ExpandableQuote) {
+                        self.output_closing("||", false);
+                        self.close_descriptor(Descriptor::ExpandableQuote)?;
+                    } else {
+                        return Err(anyhow!("Unbalanced tags"));
+                    }
+                }
+                Token::Marker(marker) => {
+                    let descriptor = match marker {
+                        Marker::Bold => Descriptor::Strong,
+                        Marker::Italic => Descriptor::Emphasis,
+                        Marker::Underline => Descriptor::Underline,
+                        Marker::Strikethrough => Descriptor::Strikethrough,
+                        Marker::Spoiler => Descriptor::Spoiler,
+                    };
+                    let marker = descriptor_closer(&descriptor, &self.config);
+                    if self.stack.last() == Some(&descriptor) {
+                        self.output_closing(marker, false);
+                        self.close_descriptor(descriptor)?;
+                    } else if self.stack.contains(&descriptor) {
+                        return Err(anyhow!("Unbalanced tags"));
+                    } else {
+                        let len = marker.len();
+                        self.count_entity();
+                        self.ensure_space(SpaceBudget::for_open(len, len, 1));
+                        self.output(marker, false);
+                        self.stack.push(descriptor);
+                    }
+                }
+                Token::Code(body) => {
+                    self.count_entity();
+                    self.ensure_space(SpaceBudget::for_open(1, 1, 1));
+                    self.stack.push(Descriptor::Code);
+                    self.output("`", false);
+                    self.output(body, false);
+                    self.output_closing("`", false);
+                    self.close_descriptor(Descriptor::Code)?;
+                }
+                Token::Pre { lang, body } => {
+                    self.start_tag(Tag::CodeBlock(CodeBlockKind::Fenced(lang.into())))?;
+                    self.output(body, false);
+                    self.output_closing("```", false);
+                    self.close_descriptor(Descriptor::CodeBlock(String::new()))?;
+                }
+                Token::Link(link) => {
+                    self.count_entity();
+                    self.write(link, false, false, false);
+                }
+            }
+            line_start = false;
+        }
+
+        if !self.stack.is_empty() {
+            return Err(anyhow!("Unbalanced tags"));
+        }
+        self.finish()
+    }
+
+    /// Room left in the last chunk returned by the previous conversion,
+    /// continuation markers included, in the unit of
+    /// [`ConverterConfig::length_metric`]. This is not a live counter: it is
+    /// set once a conversion succeeds and is 0 after one fails. Useful to
+    /// decide whether a footer can be appended to that chunk.
+    pub fn remaining_in_last_chunk(&self) -> usize {
+        self.last_chunk_remaining
+    }
+
+    /// Byte offsets in the markdown passed to the last [`Converter::go`] call
+    /// where each chunk after the first begins. Splits inside text are mapped
+    /// back through escaping; other splits report the start of the Markdown
+    /// element being rendered.
+    pub fn split_points(&self) -> &[usize] {
+        &self.split_points
+    }
+
+    /// The entities open at this point of the conversion, outermost first,
+    /// followed by the prefix the next line would start with, if any. After
+    /// a conversion cut short by [`Converter::go_take`] this is what the next
+    /// chunk would reopen.
+    #[cfg(feature = "debug-stack")]
+    pub fn debug_stack(&self) -> Vec<String> {
+        let mut stack = entity_names(&self.stack);
+        let prefix = self.line_prefix();
+        if !prefix.is_empty() {
+            stack.push(format!("Prefix({prefix:?})"));
+        }
+        stack
+    }
+
     /// Ensure the current chunk can fit the requested budget. If not, split
     /// before emitting the next content to avoid dangling markers or prefixes.
     fn ensure_space(&mut self, budget: SpaceBudget) {
@@ -252,36 +1433,188 @@ impl Converter {
         }
     }
 
+    /// Whether `descriptor` is written as plain text because the dialect
+    /// can't express it here.
+    fn is_plain(&self, descriptor: &Descriptor) -> bool {
+        self.config.dialect == Dialect::MarkdownV1
+            && (*descriptor == Descriptor::Strikethrough
+                || self
+                    .stack
+                    .iter()
+                    .any(|desc| !matches!(desc, Descriptor::Plain | Descriptor::ExpandableQuote)))
+    }
+
+    /// Count an entity about to be opened, first starting a new chunk when
+    /// the current one already holds `max_entities_per_chunk` of them.
+    fn count_entity(&mut self) {
+        if let Some(limit) = self.config.max_entities_per_chunk
+            && self.chunk_entities >= limit
+            && self.current_len() > self.chunk_lead_len
+        {
+            self.split_chunk();
+        }
+        self.chunk_entities += 1;
+    }
+
+    /// Whether a split of the current chunk cuts the output short.
+    fn ellipsis_pending(&self) -> bool {
+        self.ellipsis_on_split && self.chunk_limit == Some(self.result.len())
+    }
+
     /// Compute remaining writable space in the current chunk after accounting
     /// for any pending prefixes (newline/quotes) and the closers of the open
     /// descriptors. When `skip_top` is true we ignore the closer of the topmost
     /// descriptor (used while writing that closer itself).
     fn available_space(&self, skip_top: bool) -> usize {
-        let current_len = self.result.last().map(|s| s.len()).unwrap_or(0);
-        let reserved = self.pending_prefix_len() + self.closers_len(skip_top);
-        self.max_len.saturating_sub(current_len + reserved)
+        self.config
+            .max_len
+            .saturating_sub(self.chunk_overhead(skip_top))
+    }
+
+    /// Length of the chunk so far plus what still has to follow the text:
+    /// pending line prefixes and the closers of open entities.
+    fn chunk_overhead(&self, skip_top: bool) -> usize {
+        let mut reserved = self.pending_prefix_len() + self.closers_len(skip_top);
+        if self.expandable_quote_pending {
+            // The `||` that closes the quote once its first line is written.
+            reserved += self.measure("||");
+        }
+        if self.ellipsis_pending() {
+            reserved += self.measure(ELLIPSIS);
+        }
+        self.current_len() + reserved
     }
 
     fn new_line(&mut self) {
         // Any newline ends the "after prefix" state to avoid suppressing later breaks.
         self.after_list_prefix = false;
-        let last_len = self.result.last().map(|s| s.len()).unwrap_or(0);
+        let last_len = self.current_len();
         if last_len == 0 {
             return;
         }
-        let needed = 1 + self.quote_level as usize;
-        if last_len + needed > self.max_len {
+        let mut needed = self.measure("\n") + self.measure(&self.line_prefix());
+        if self.expandable_quote_pending {
+            needed += self.measure("||");
+        }
+        if last_len + needed > self.config.max_len {
             // Start a fresh chunk instead of emitting an empty newline-only tail.
             self.split_chunk();
             return;
         }
 
+        self.push_line_break();
+    }
+
+    /// Start the line of a new block, leaving a blank line before it unless
+    /// `compact_spacing` is set and a line break is already pending.
+    fn block_gap(&mut self) {
+        if !self.config.compact_spacing || !self.add_new_line {
+            self.new_line();
+        }
+    }
+
+    /// Start a new line in the current chunk. A preceding line that stayed
+    /// empty loses its continuation indent so blank lines carry no spaces.
+    fn push_line_break(&mut self) {
+        let line_prefix = self.line_prefix();
+        let in_code_block = self.in_code_block();
         let last = self.result.last_mut().unwrap();
+        let line_start = last.rfind('\n').map_or(0, |idx| idx + 1);
+        let blank = is_blank_line(&last[line_start..], &self.config.quote_prefix);
+        if line_start > 0 && blank && !in_code_block {
+            last.truncate(last.trim_end_matches(' ').len());
+        }
         last.push('\n');
-        if self.quote_level > 0 {
-            last.push_str(&">".repeat(self.quote_level as usize));
+        last.push_str(&line_prefix);
+        self.quote_line_started();
+    }
+
+    /// Note that a quoted line was started, which opens an expandable quote
+    /// whose prefix was pending.
+    fn quote_line_started(&mut self) {
+        self.expandable_quote_start = false;
+        if self.expandable_quote_pending && self.quote_level > 0 {
+            self.expandable_quote_pending = false;
+            // Inline entities reopened on this line go inside the quote.
+            let outer = self
+                .stack
+                .iter()
+                .rposition(|desc| *desc == Descriptor::ExpandableQuote)
+                .map_or(0, |idx| idx + 1);
+            self.stack.insert(outer, Descriptor::ExpandableQuote);
+        }
+    }
+
+    fn count_heading_width(&mut self, txt: &str) {
+        if self
+            .stack
+            .iter()
+            .any(|d| matches!(d, Descriptor::Heading(_)))
+        {
+            self.heading_width += txt.chars().count();
+        }
+    }
+
+    /// Put a line as long as the heading just closed under it, cut to what
+    /// fits on one line of a chunk.
+    fn write_heading_underline(&mut self) {
+        let room = self
+            .fresh_chunk_space(false)
+            .saturating_sub(self.measure(&self.line_prefix()));
+        let width = self
+            .heading_width
+            .min(room / self.measure(HEADING_UNDERLINE));
+        if width == 0 {
+            return;
+        }
+        let underline = HEADING_UNDERLINE.repeat(width);
+        let newline_len = self.measure("\n") + self.quote_markers_len();
+        self.ensure_space(SpaceBudget::for_prefix(
+            newline_len,
+            self.measure(&underline),
+        ));
+        self.new_line();
+        self.write(&underline, false, false, false);
+    }
+
+    fn in_code_block(&self) -> bool {
+        self.stack
+            .iter()
+            .any(|d| matches!(d, Descriptor::CodeBlock(_)))
+    }
+
+    /// Text that starts every new line inside the current chunk: the quote
+    /// markers, then spaces aligning list item continuations under the item
+    /// text (except inside code blocks, which Telegram can't indent).
+    fn line_prefix(&self) -> String {
+        let mut prefix = self.quote_prefix();
+        if !self.opening_code_block
+            && !self.in_code_block()
+            && let Some(width) = self.list_stack.last().and_then(|s| s.item_width)
+        {
+            prefix.push_str(&" ".repeat(width));
+        }
+        prefix
+    }
+
+    /// Quote markers that start a line at the current quote level.
+    fn quote_prefix(&self) -> String {
+        let mut markers = self.config.quote_prefix.repeat(self.quote_level);
+        if self.config.quote_space && self.quote_level > 0 {
+            markers.push(' ');
+        }
+        if self.expandable_quote_start {
+            format!("**{markers}")
+        } else {
+            markers
         }
     }
+
+    fn quote_markers_len(&self) -> usize {
+        let space = self.config.quote_space && self.quote_level > 0;
+        self.measure(&self.config.quote_prefix) * self.quote_level + usize::from(space)
+    }
+
     fn output(&mut self, txt: &str, escape: bool) {
         self.write(txt, escape, true, false);
     }
@@ -296,68 +1629,149 @@ impl Converter {
     /// Core write function. `breakable` toggles word-boundary splitting.
     /// `skip_top` omits the top descriptor when reserving closer space.
     fn write(&mut self, txt: &str, escape: bool, breakable: bool, skip_top: bool) {
-        let owned: Option<String> = if escape { Some(escape_str(txt)) } else { None };
+        let context = self.escape_context();
+        let owned: Option<String> = match (escape, context) {
+            (false, _) => None,
+            (true, EntityContext::Code) => Some(escape_code_content(txt, &self.config)),
+            (true, _) => Some(escape_in(txt, context, &self.config)),
+        };
         let mut remaining: &str = owned.as_deref().unwrap_or(txt);
+        let written_len = remaining.len();
+        let source_start = self.source_pos;
 
         while !remaining.is_empty() {
             // Make sure pending prefixes and closers still fit.
-            let current_len = self.result.last().map(|s| s.len()).unwrap_or(0);
+            let current_len = self.current_len();
             let available = self.available_space(skip_top);
-            if available == 0 {
+            if available == 0 && current_len > self.chunk_lead_len {
                 self.split_chunk();
+                // Whitespace the split happened at doesn't start the next chunk.
+                if breakable && !self.in_code_block() {
+                    remaining = remaining.trim_start_matches([' ', '\t']);
+                }
                 continue;
             }
 
-            let take = if breakable {
-                let allow_hard_split = current_len == 0;
-                let sp = split_point(remaining, available, allow_hard_split);
-                if sp == 0 {
-                    // No whitespace before limit. If there is existing content, start a new chunk
-                    // so we don't split mid-word. Otherwise, force a split (single very long word).
-                    if current_len > 0 {
-                        self.split_chunk();
-                        continue;
-                    }
-                    available
+            let fit = self.fit_len(remaining, available);
+            let (take, overflow_len) = if breakable {
+                let sp = if self.in_code_block() {
+                    code_split_point(remaining, fit)
                 } else {
-                    sp
+                    split_point(remaining, fit, false)
+                };
+                if sp > 0 {
+                    (sp, None)
+                } else if current_len > self.chunk_lead_len
+                    && self.measure(first_word(remaining)) <= self.fresh_chunk_space(skip_top)
+                {
+                    // No whitespace before limit. Start a new chunk so we don't split mid-word.
+                    self.split_chunk();
+                    continue;
+                } else {
+                    // A single word longer than a whole chunk.
+                    (0, Some(first_word(remaining).len()))
                 }
-            } else if remaining.len() > available && remaining.len() <= self.max_len {
-                // Keep unbreakable text together if it can fit a fresh chunk.
-                self.split_chunk();
-                continue;
+            } else if fit < remaining.len() {
+                if current_len > self.chunk_lead_len
+                    && self.measure(remaining) <= self.fresh_chunk_space(skip_top)
+                {
+                    // Keep unbreakable text together if it can fit a fresh chunk.
+                    self.split_chunk();
+                    continue;
+                }
+                (0, Some(remaining.len()))
             } else {
-                remaining.len().min(available)
+                (fit, None)
             };
 
-            if take == 0 {
+            let take = match overflow_len {
+                None => take,
+                Some(token_len) => match self.config.overflow {
+                    OverflowStrategy::HardSplit => hard_split_len(remaining, fit),
+                    OverflowStrategy::Error => {
+                        let needed = self.measure(&remaining[..token_len]);
+                        self.overflow_len.get_or_insert(needed);
+                        hard_split_len(remaining, fit)
+                    }
+                    OverflowStrategy::Truncate => {
+                        let keep = available.saturating_sub(self.measure(ELLIPSIS));
+                        let keep = hard_split_len(remaining, self.fit_len(remaining, keep));
+                        self.flush_pending_prefix();
+                        let last = self.result.last_mut().unwrap();
+                        last.push_str(&remaining[..keep]);
+                        last.push_str(ELLIPSIS);
+                        if self.after_list_prefix {
+                            self.list_body_written = true;
+                        }
+                        remaining = remaining[token_len..].trim_start_matches([' ', '\t']);
+                        if escape {
+                            self.advance_source(
+                                source_start,
+                                txt,
+                                written_len - remaining.len(),
+                                context,
+                            );
+                        }
+                        if !remaining.is_empty() {
+                            self.split_chunk();
+                        }
+                        continue;
+                    }
+                },
+            };
+
+            let take = if take > 0 {
+                take
+            } else if current_len <= self.chunk_lead_len {
+                // Not even one character fits next to the markup a fresh
+                // chunk repeats, so no split can help. Emit it anyway to
+                // finish the run, then fail with the length it needed.
+                let take = remaining.chars().next().map_or(0, char::len_utf8);
+                let needed = self.chunk_overhead(skip_top) + self.measure(&remaining[..take]);
+                self.overflow_len.get_or_insert(needed);
+                take
+            } else {
                 self.split_chunk();
                 continue;
-            }
+            };
 
             self.flush_pending_prefix();
             let (part, rest) = remaining.split_at(take);
 
             // Trim whitespace that would straddle the split for breakable text.
-            let (part, rest) = if breakable && !rest.is_empty() {
+            let (part, rest) = if breakable && !rest.is_empty() && !self.in_code_block() {
                 let soft_ws = |c: char| c == ' ' || c == '\t';
-                (
-                    part.trim_end_matches(soft_ws),
-                    rest.trim_start_matches(soft_ws),
-                )
+                let part = match self.config.chunk_trim {
+                    ChunkTrim::None => part,
+                    ChunkTrim::Trailing | ChunkTrim::Both => part.trim_end_matches(soft_ws),
+                };
+                (part, rest.trim_start_matches(soft_ws))
             } else {
                 (part, rest)
             };
 
+            // Soft hyphens only matter where a word was broken at one.
+            let in_code = matches!(
+                self.stack.last(),
+                Some(Descriptor::Code | Descriptor::CodeBlock(_))
+            );
+            let part = if breakable && !in_code {
+                without_soft_hyphens(part, !rest.is_empty())
+            } else {
+                Cow::Borrowed(part)
+            };
             if !part.is_empty() {
                 let last = self.result.last_mut().unwrap();
-                last.push_str(part);
+                last.push_str(&part);
                 if self.after_list_prefix {
                     self.list_body_written = true;
                 }
             }
 
             remaining = rest;
+            if escape {
+                self.advance_source(source_start, txt, written_len - remaining.len(), context);
+            }
 
             if !remaining.is_empty() {
                 self.split_chunk();
@@ -365,16 +1779,145 @@ impl Converter {
         }
     }
 
+    /// Write text, turning the autolink literals in it into links.
+    fn write_autolinked(&mut self, text: &str) {
+        let mut plain_start = 0;
+        let mut idx = 0;
+        while idx < text.len() {
+            let at_word_start = text[..idx]
+                .chars()
+                .next_back()
+                .is_none_or(|c| c.is_whitespace() || c == '(');
+            if at_word_start && let Some(len) = autolink_literal_len(&text[idx..]) {
+                if plain_start < idx {
+                    self.output(&text[plain_start..idx], true);
+                }
+                let literal = &text[idx..idx + len];
+                let url = if literal.starts_with("www.") {
+                    format!("http://{literal}")
+                } else {
+                    literal.to_string()
+                };
+                let mut label = LinkLabel::default();
+                label.push_text(literal, &self.config);
+                self.write_link(&label, &url);
+                idx += len;
+                plain_start = idx;
+            } else {
+                idx += text[idx..].chars().next().map_or(1, char::len_utf8);
+            }
+        }
+        if plain_start < text.len() {
+            self.output(&text[plain_start..], true);
+        }
+    }
+
+    /// Write a link as one unbreakable piece. With `links_as_text`, or when
+    /// the link can't fit even an empty chunk, it becomes plain text instead.
+    fn write_link(&mut self, label: &LinkLabel, url: &str) {
+        let url = &*self.rewrite_url(url);
+        if !self.config.links_as_text {
+            self.count_entity();
+            let mut link = format!("[{}](", label.markup);
+            push_escaped_in(&mut link, url, EntityContext::LinkUrl, &self.config);
+            link.push(')');
+            if self.measure(&link) <= self.config.max_len {
+                self.write(&link, false, false, false);
+                return;
+            }
+        }
+        let label = &label.text;
+        let bare_url = url
+            .strip_prefix("mailto:")
+            .or_else(|| url.strip_prefix("http://"));
+        if label == url || bare_url == Some(label) {
+            self.output(label, true);
+        } else {
+            self.output(&format!("{label} ({url})"), true);
+        }
+    }
+
+    fn rewrite_url<'u>(&self, url: &'u str) -> Cow<'u, str> {
+        match &self.config.url_rewriter {
+            Some(rewriter) => Cow::Owned(rewriter.rewrite(url)),
+            None => Cow::Borrowed(url),
+        }
+    }
+
+    /// Move `source_pos` past the source text whose escaped form spans
+    /// `escaped_len` bytes of a write that started at `source_start`.
+    fn advance_source(
+        &mut self,
+        source_start: usize,
+        txt: &str,
+        escaped_len: usize,
+        context: EntityContext,
+    ) {
+        self.source_pos =
+            source_start + unescaped_prefix_len(txt, escaped_len, context, &self.config);
+    }
+
+    fn measure(&self, text: &str) -> usize {
+        let len = self.config.length_metric.measure(text);
+        if self.config.newline == "\n" {
+            return len;
+        }
+        // Line breaks are written as `newline` once the chunks are done.
+        let breaks = text.matches('\n').count();
+        len - breaks + breaks * self.config.length_metric.measure(&self.config.newline)
+    }
+
+    fn current_len(&self) -> usize {
+        self.result.last().map_or(0, |s| self.measure(s))
+    }
+
+    /// Byte length of the longest prefix of `text` that measures at most
+    /// `limit`. Always lands on a char boundary.
+    fn fit_len(&self, text: &str, limit: usize) -> usize {
+        if self.config.newline == "\n"
+            && let LengthMetric::Bytes = self.config.length_metric
+        {
+            return floor_char_boundary(text, limit);
+        }
+        let mut used = 0;
+        for (idx, ch) in text.char_indices() {
+            used += match ch {
+                '\n' => self.measure("\n"),
+                _ => self
+                    .config
+                    .length_metric
+                    .measure(ch.encode_utf8(&mut [0; 4])),
+            };
+            if used > limit {
+                return idx;
+            }
+        }
+        text.len()
+    }
+
+    /// Escaping context of text written at the current position.
+    fn escape_context(&self) -> EntityContext {
+        match self.stack.last() {
+            Some(Descriptor::Code | Descriptor::CodeBlock(_)) => EntityContext::Code,
+            // Legacy Markdown takes everything up to the closing marker
+            // literally, so nothing inside an entity can be escaped.
+            Some(Descriptor::Strong | Descriptor::Emphasis | Descriptor::Heading(_))
+                if self.config.dialect == Dialect::MarkdownV1 =>
+            {
+                EntityContext::Code
+            }
+            _ => EntityContext::Text,
+        }
+    }
+
     /// Number of prefix characters that would be inserted before the next write.
     fn pending_prefix_len(&self) -> usize {
         let mut len = 0;
-        if self.add_new_line {
-            len += 1; // the newline itself
-            if self.quote_level > 0 {
-                len += self.quote_level as usize;
-            }
-        } else if self.result.last().map(|s| s.is_empty()).unwrap_or(true) && self.quote_level > 0 {
-            len += self.quote_level as usize;
+        let chunk_empty = self.result.last().map(|s| s.is_empty()).unwrap_or(true);
+        if self.add_new_line && !chunk_empty {
+            len += self.measure("\n") + self.measure(&self.line_prefix()); // the newline and its prefix
+        } else if chunk_empty && self.quote_level > 0 {
+            len += self.measure(&self.quote_prefix());
         }
 
         len
@@ -382,25 +1925,37 @@ impl Converter {
 
     /// Emit any pending newline and quote prefix.
     fn flush_pending_prefix(&mut self) {
-        let last = self.result.last_mut().unwrap();
-        if self.add_new_line {
-            last.push('\n');
-            if self.quote_level > 0 {
-                last.push_str(&">".repeat(self.quote_level as usize));
-            }
+        // A chunk never starts with a line break.
+        if self.add_new_line && self.current_len() > 0 {
+            self.push_line_break();
             self.add_new_line = false;
-        } else if last.is_empty() && self.quote_level > 0 {
-            last.push_str(&">".repeat(self.quote_level as usize));
+            return;
+        }
+        self.add_new_line = false;
+        let quote_prefix = self.quote_prefix();
+        let last = self.result.last_mut().unwrap();
+        if last.is_empty() && self.quote_level > 0 {
+            last.push_str(&quote_prefix);
+            self.quote_line_started();
         }
     }
 
     fn split_chunk(&mut self) {
+        self.split_points.push(self.source_pos);
         let heading_pending =
             !self.heading_body_written && matches!(self.stack.last(), Some(Descriptor::Heading(_)));
-        if !heading_pending {
+        if !heading_pending && self.config.chunk_trim != ChunkTrim::None && !self.in_code_block() {
             self.trim_trailing_ws();
         }
 
+        let carried_markers = self.take_empty_markers();
+        self.take_empty_expandable_quote();
+        if !carried_markers.is_empty()
+            && !heading_pending
+            && self.config.chunk_trim != ChunkTrim::None
+        {
+            self.trim_trailing_ws();
+        }
         let (carry_list_prefix, carry_list_prefix_newline) = self.take_dangling_list_prefix();
         let carried_heading = self.take_dangling_heading();
 
@@ -409,12 +1964,20 @@ impl Converter {
             self.stack.pop();
         }
 
+        if !self.in_code_block() {
+            self.trim_trailing_blank_lines();
+        }
+        if self.ellipsis_pending() {
+            self.result.last_mut().unwrap().push_str(ELLIPSIS);
+        }
         self.write_closers();
+        let closed = self.stack.clone();
 
         // Restore carried heading to the stack for reopening.
         if let Some(ref h) = carried_heading {
             self.stack.push(h.clone());
         }
+        self.stack.extend(carried_markers.into_iter().rev());
         self.result.push(String::new());
         self.add_new_line = carry_list_prefix_newline;
         self.reopen_descriptors();
@@ -426,6 +1989,17 @@ impl Converter {
             self.after_list_prefix = true;
             self.list_body_written = false;
         }
+        self.chunk_lead_len = self.current_len();
+        self.split_stacks.push((closed, self.stack.clone()));
+        // The reopened entities, plus the quote the chunk continues.
+        self.chunk_entities = self
+            .stack
+            .iter()
+            .filter(|desc| **desc != Descriptor::Plain)
+            .count()
+            + usize::from(
+                self.quote_level > 0 && !self.stack.contains(&Descriptor::ExpandableQuote),
+            );
     }
 
     /// Trim trailing spaces and tabs from the current chunk.
@@ -437,6 +2011,33 @@ impl Converter {
         }
     }
 
+    /// Drop the space after a list marker that ends the chunk, as nothing
+    /// follows it on its line: the item is empty or starts with a block.
+    fn trim_bare_list_prefix(&mut self) {
+        if self
+            .result
+            .last()
+            .is_some_and(|last| last.ends_with(&self.last_list_prefix))
+        {
+            self.trim_trailing_ws();
+        }
+    }
+
+    /// Drop line breaks (with their quote markers and indents) left at the
+    /// end of the current chunk by content that moved to the next one, and
+    /// the quote markers of a chunk with nothing else in it.
+    fn trim_trailing_blank_lines(&mut self) {
+        let last = self.result.last_mut().unwrap();
+        while let Some(idx) = last.rfind('\n')
+            && is_blank_line(&last[idx + 1..], &self.config.quote_prefix)
+        {
+            last.truncate(idx);
+        }
+        if !last.contains('\n') && is_blank_line(last, &self.config.quote_prefix) {
+            last.clear();
+        }
+    }
+
     /// If the current chunk ends with a list prefix that has no body yet,
     /// remove and carry it over to the next chunk so markers never dangle.
     fn take_dangling_list_prefix(&mut self) -> (Option<String>, bool) {
@@ -444,9 +2045,12 @@ impl Converter {
             let prefix = self.last_list_prefix.clone();
             let mut carry_newline = false;
             if let Some(last) = self.result.last_mut() {
-                let len = last.len();
-                if len >= prefix.len() {
-                    last.truncate(len - prefix.len());
+                // The split may already have trimmed the space after the marker.
+                if let Some(before) = last
+                    .strip_suffix(prefix.as_str())
+                    .or_else(|| last.strip_suffix(prefix.trim_end()))
+                {
+                    last.truncate(before.len());
                 }
                 while last.ends_with('\n') || last.ends_with('\r') {
                     last.pop();
@@ -466,22 +2070,99 @@ impl Converter {
             return None;
         }
         if let Some(Descriptor::Heading(level)) = self.stack.last() {
-            let opener = heading_prefix(*level);
-            if let Some(last) = self.result.last_mut() {
-                if last.ends_with(opener) {
-                    last.truncate(last.len() - opener.len());
-                    return Some(Descriptor::Heading(*level));
-                }
+            let opener = heading_prefix(*level, &self.config);
+            if let Some(last) = self.result.last_mut()
+                && last.ends_with(opener)
+            {
+                last.truncate(last.len() - opener.len());
+                return Some(Descriptor::Heading(*level));
             }
         }
         None
     }
 
+    /// Pop the inline entities opened at the very end of the chunk with no
+    /// text yet, removing their markers so they are only opened in the next
+    /// chunk. Closing them here would leave empty pairs like `*__*`, which
+    /// Telegram reads as an underline. Returns them innermost first.
+    fn take_empty_markers(&mut self) -> Vec<Descriptor> {
+        let mut taken = Vec::new();
+        while let Some(desc) = self.stack.last() {
+            let opener = match desc {
+                Descriptor::Strong => "*",
+                Descriptor::Emphasis => "_",
+                Descriptor::Strikethrough => "~",
+                Descriptor::Underline => "__",
+                Descriptor::Spoiler => "||",
+                _ => break,
+            };
+            let Some(last) = self.result.last_mut() else {
+                break;
+            };
+            let Some(before) = last.strip_suffix(opener) else {
+                break;
+            };
+            let backslashes = before.bytes().rev().take_while(|&b| b == b'\\').count();
+            if backslashes % 2 == 1 {
+                break;
+            }
+            last.truncate(before.len());
+            taken.push(self.stack.pop().unwrap());
+        }
+        taken
+    }
+
+    /// Take back an expandable quote whose first line was just started at
+    /// the end of the chunk, so it opens in the next one instead of ending
+    /// this one as an empty `**>||`.
+    fn take_empty_expandable_quote(&mut self) {
+        if self.stack.last() != Some(&Descriptor::ExpandableQuote) {
+            return;
+        }
+        let opener = format!("**{}", self.line_prefix());
+        if let Some(last) = self.result.last_mut()
+            && let Some(before) = last.strip_suffix(&opener)
+            && !before.trim().is_empty()
+        {
+            last.truncate(before.len());
+            self.stack.pop();
+            self.expandable_quote_pending = true;
+            self.expandable_quote_start = true;
+        }
+    }
+
+    /// Remove `opener` from the end of the chunk when nothing was written
+    /// after it, which only happens once stripping characters left the
+    /// entity without text. Returns whether it was removed.
+    fn drop_empty_opener(&mut self, opener: &str) -> bool {
+        if !self.config.strip_emoji && !self.config.strip_invisible_controls {
+            return false;
+        }
+        let Some(last) = self.result.last_mut() else {
+            return false;
+        };
+        let Some(before) = last.strip_suffix(opener) else {
+            return false;
+        };
+        // An odd run of backslashes means the marker is escaped text.
+        let backslashes = before.bytes().rev().take_while(|&b| b == b'\\').count();
+        if backslashes % 2 == 1 {
+            return false;
+        }
+        last.truncate(before.len());
+        true
+    }
+
     fn write_closers(&mut self) {
         if self.stack.is_empty() {
             return;
         }
-        let closers: Vec<&str> = self.stack.iter().rev().map(descriptor_closer).collect();
+        let closers: Vec<&str> = self
+            .stack
+            .iter()
+            .rev()
+            .map(|desc| descriptor_closer(desc, &self.config))
+            .collect();
         let last = self.result.last_mut().unwrap();
         for closer in closers {
             last.push_str(closer);
@@ -496,26 +2177,75 @@ impl Converter {
         let descriptors = self.stack.clone();
         for desc in descriptors {
             match desc {
-                Descriptor::Strong => self.output("*", false),
-                Descriptor::Emphasis => self.output("_", false),
-                Descriptor::Strikethrough => self.output("~~", false),
-                Descriptor::Code => self.output("`", false),
-                Descriptor::Heading(level) => self.output(heading_prefix(level), false),
+                Descriptor::Strong => self.push_markup("*"),
+                Descriptor::Emphasis => self.push_markup("_"),
+                Descriptor::Strikethrough => self.push_markup("~"),
+                Descriptor::Code => self.push_markup("`"),
+                Descriptor::Heading(level) => self.push_markup(heading_prefix(level, &self.config)),
+                Descriptor::Underline => self.push_markup("__"),
+                Descriptor::Spoiler => self.push_markup("||"),
+                Descriptor::ExpandableQuote => self.expandable_quote_start = true,
+                Descriptor::Plain => {}
                 Descriptor::CodeBlock(lang) => {
-                    self.output("```", false);
-                    self.output(&lang, true);
+                    self.push_markup("```");
+                    self.push_markup(&lang);
                     self.add_new_line = true;
                 }
             }
         }
     }
 
+    /// Write markup whose room was already budgeted, such as the openers a
+    /// fresh chunk repeats, without ever splitting it. Splitting there
+    /// would reopen the same markup in the next chunk, endlessly.
+    fn push_markup(&mut self, markup: &str) {
+        self.flush_pending_prefix();
+        self.result.last_mut().unwrap().push_str(markup);
+    }
+
+    /// Room for content in a chunk that starts by reopening the entities
+    /// open now, so a token that doesn't fit it won't fit after a split either.
+    fn fresh_chunk_space(&self, skip_top: bool) -> usize {
+        self.config
+            .max_len
+            .saturating_sub(self.fresh_chunk_overhead(skip_top))
+    }
+
+    /// Markup every chunk repeats while the entities open now stay open:
+    /// their openers and closers and the quote markers.
+    fn fresh_chunk_overhead(&self, skip_top: bool) -> usize {
+        let openers_len: usize = self
+            .stack
+            .iter()
+            .map(|desc| match desc {
+                Descriptor::Heading(level) => self.measure(heading_prefix(*level, &self.config)),
+                Descriptor::CodeBlock(lang) => 3 + self.measure(lang) + self.measure("\n"),
+                Descriptor::ExpandableQuote => 2,
+                _ => self.measure(descriptor_closer(desc, &self.config)),
+            })
+            .sum();
+        let mut reserved = openers_len + self.closers_len(skip_top) + self.quote_markers_len();
+        if self.ellipsis_on_split {
+            reserved += self.measure(ELLIPSIS);
+        }
+        reserved
+    }
+
     fn closers_len(&self, skip_top: bool) -> usize {
         let mut iter = self.stack.iter().rev();
         if skip_top {
             iter.next();
         }
-        iter.map(descriptor_closer).map(str::len).sum()
+        iter.map(|desc| self.measure(descriptor_closer(desc, &self.config)))
+            .sum()
+    }
+
+    /// The `.` after a list number, escaped as the dialect requires.
+    fn list_number_dot(&self) -> &'static str {
+        match self.config.dialect {
+            Dialect::MarkdownV2 => "\\.",
+            Dialect::MarkdownV1 => ".",
+        }
     }
 
     fn list_prefix(&mut self) -> String {
@@ -523,13 +2253,28 @@ impl Converter {
         let extra_levels = self.list_stack.last().map(|s| s.extra_levels).unwrap_or(0);
         let indent_len = (base_levels + extra_levels) * 2;
         let indent = " ".repeat(indent_len);
-        if let Some(state) = self.list_stack.last_mut() {
-            if state.ordered {
-                let number = state.start + state.items as u64;
-                return format!("{}{}\\. ", indent, number);
+        if let Some(state) = self.list_stack.last()
+            && state.ordered
+        {
+            let number = state.number();
+            if self.config.legal_numbering {
+                // Numbers of the items of the directly enclosing ordered
+                // lists; their counters already include the open item.
+                let mut path: Vec<String> = self.list_stack[..self.list_stack.len() - 1]
+                    .iter()
+                    .rev()
+                    .take_while(|parent| parent.ordered)
+                    .map(|parent| parent.number().saturating_sub(1).to_string())
+                    .collect();
+                if !path.is_empty() {
+                    path.reverse();
+                    let dot = self.list_number_dot();
+                    return format!("{}{}{}{} ", indent, path.join(dot), dot, number);
+                }
             }
+            return format!("{}{}{} ", indent, number, self.list_number_dot());
         }
-        format!("{}⦁ ", indent)
+        format!("{}{} ", indent, self.config.bullet)
     }
 
     fn start_tag(&mut self, tag: Tag) -> anyhow::Result<()> {
@@ -544,42 +2289,78 @@ impl Converter {
                     // Continue on the same line after a list marker.
                     self.after_list_prefix = false;
                     self.after_heading = false;
-                } else if self.after_heading {
-                    self.new_line();
-                    self.after_heading = false;
                 } else {
-                    self.new_line();
+                    self.after_heading = false;
+                    self.block_gap();
                 }
 
                 debug_log!("Paragraph");
             }
             Tag::Heading { level, .. } => {
+                self.count_entity();
                 self.ensure_space(SpaceBudget::for_open(
-                    heading_prefix(level).len(),
-                    heading_closer(level).len(),
+                    self.measure(heading_prefix(level, &self.config)),
+                    self.measure(heading_closer(level, &self.config)),
                     1,
                 ));
 
-                self.new_line();
-                self.output(heading_prefix(level), false);
+                self.trim_bare_list_prefix();
+                self.block_gap();
+                // The prefix is reopened in every chunk the heading spans, so
+                // a chunk must fit it with the closer and one character.
+                let prefix = heading_prefix(level, &self.config);
+                let needed = self.fresh_chunk_overhead(false)
+                    + self.measure(prefix)
+                    + self.measure(heading_closer(level, &self.config))
+                    + 1;
+                if needed > self.config.max_len {
+                    self.overflow_len.get_or_insert(needed);
+                }
+                self.push_markup(prefix);
                 self.stack.push(Descriptor::Heading(level));
                 self.heading_body_written = false;
+                self.heading_width = 0;
 
                 debug_log!("Heading");
             }
-            Tag::BlockQuote(_) => {
+            Tag::BlockQuote(kind) => {
                 // If a blank line was pending, flush it before entering the blockquote
-                // so the empty line stays outside the quoted area.
-                if self.add_new_line {
+                // so the empty line stays outside the quoted area. Without
+                // blank lines, the pending break starts the first quoted line.
+                if self.add_new_line && !self.config.compact_spacing {
                     self.flush_pending_prefix();
                 }
+                self.count_entity();
+                if self.after_list_prefix {
+                    // Quote markers must start a line, so a quote that starts
+                    // a list item goes below its marker.
+                    self.trim_bare_list_prefix();
+                    self.after_list_prefix = false;
+                }
                 self.quote_level += 1;
+                // GitHub alerts (`> [!NOTE]`) get a bold title line.
+                if let Some(kind) = kind {
+                    self.block_gap();
+                    self.count_entity();
+                    self.ensure_space(SpaceBudget::for_open(1, 1, 1));
+                    self.output("*", false);
+                    self.stack.push(Descriptor::Strong);
+                    self.output(alert_title(kind), true);
+                    self.output_closing("*", false);
+                    self.close_descriptor(Descriptor::Strong)?;
+                }
 
                 debug_log!("BlockQuote");
             }
             Tag::CodeBlock(kind) => {
-                let lang = match kind {
-                    CodeBlockKind::Fenced(lang) => lang.to_string(),
+                // Telegram takes the language from the first word of the
+                // info string; it is part of the code entity, so it's kept
+                // escaped as code.
+                let mut lang = match kind {
+                    CodeBlockKind::Fenced(info) => escape_code_content(
+                        info.split_whitespace().next().unwrap_or(""),
+                        &self.config,
+                    ),
                     CodeBlockKind::Indented => String::new(),
                 };
 
@@ -587,12 +2368,32 @@ impl Converter {
                 // on a fresh chunk so we don't end up force‑splitting the first
                 // line of code mid‑word. Reserve space for the opening fence,
                 // closing fence, and a little body headroom.
+                // Fences must start a line, even right after the text of a
+                // tight list item.
+                if self.current_len() > 0 {
+                    self.add_new_line = true;
+                }
+
+                self.count_entity();
                 const MIN_CODE_BODY_HEADROOM: usize = 4;
-                let header_len = 3 + lang.len(); // "```" + lang
+                // A language too long to reopen in every chunk is dropped.
+                let fence_len =
+                    3 + self.measure(&lang) + self.measure("\n") + MIN_CODE_BODY_HEADROOM + 3;
+                if fence_len > self.fresh_chunk_space(false) {
+                    lang.clear();
+                }
+                let header_len = 3 + self.measure(&lang); // "```" + lang
                 self.ensure_space(SpaceBudget::for_open(header_len, 3, MIN_CODE_BODY_HEADROOM));
+                self.trim_bare_list_prefix();
+                // A split may have carried a list marker over to a new chunk.
+                if self.current_len() > 0 {
+                    self.add_new_line = true;
+                }
 
+                self.opening_code_block = true;
                 self.output("```", false);
-                self.output(&lang, true);
+                self.opening_code_block = false;
+                self.output(&lang, false);
                 self.add_new_line = true;
                 self.stack.push(Descriptor::CodeBlock(lang));
 
@@ -633,12 +2434,13 @@ impl Converter {
                 }
                 let prefix = self.list_prefix();
                 // Avoid leaving the prefix at the end of the chunk with no body.
-                self.ensure_space(SpaceBudget::for_prefix(prefix.len(), 1));
+                let prefix_len = self.measure(&prefix);
+                self.ensure_space(SpaceBudget::for_prefix(prefix_len, 1));
                 // Ensure the prefix fits; if not, split first.
                 let pending_prefix = self.pending_prefix_len();
                 let closers_len = self.closers_len(false);
-                let current_len = self.result.last().map(|s| s.len()).unwrap_or(0);
-                if current_len + pending_prefix + closers_len + prefix.len() >= self.max_len {
+                let current_len = self.current_len();
+                if current_len + pending_prefix + closers_len + prefix_len >= self.config.max_len {
                     self.split_chunk();
                 }
                 self.flush_pending_prefix();
@@ -648,6 +2450,8 @@ impl Converter {
                 self.list_body_written = false;
 
                 if let Some(state) = self.list_stack.last_mut() {
+                    // Escapes in ordered markers are invisible, so skip them.
+                    state.item_width = Some(prefix.chars().filter(|&c| c != '\\').count());
                     if state.ordered {
                         // track count to decide on follow-up indentation heuristics
                         state.items += 1;
@@ -661,6 +2465,8 @@ impl Converter {
                 debug_log!("FootnoteDefinition");
             }
             Tag::Table(_) => {
+                self.table = Some(TableState::default());
+
                 debug_log!("Table");
             }
             Tag::TableHead => {
@@ -678,44 +2484,79 @@ impl Converter {
             Tag::Superscript => {
                 debug_log!("Superscript");
             }
+            Tag::Emphasis if self.is_plain(&Descriptor::Emphasis) => {
+                self.stack.push(Descriptor::Plain);
+            }
             Tag::Emphasis => {
+                self.count_entity();
                 self.ensure_space(SpaceBudget::for_open(1, 1, 1));
                 self.output("_", false);
                 self.stack.push(Descriptor::Emphasis);
 
                 debug_log!("Emphasis");
             }
+            Tag::Strong if self.is_plain(&Descriptor::Strong) => {
+                self.stack.push(Descriptor::Plain);
+            }
             Tag::Strong => {
+                self.count_entity();
                 self.ensure_space(SpaceBudget::for_open(1, 1, 1));
                 self.output("*", false);
                 self.stack.push(Descriptor::Strong);
 
                 debug_log!("Strong");
             }
+            Tag::Strikethrough if self.is_plain(&Descriptor::Strikethrough) => {
+                self.stack.push(Descriptor::Plain);
+            }
             Tag::Strikethrough => {
-                self.ensure_space(SpaceBudget::for_open(2, 2, 1));
-                self.output("~~", false);
+                self.count_entity();
+                self.ensure_space(SpaceBudget::for_open(1, 1, 1));
+                self.output("~", false);
                 self.stack.push(Descriptor::Strikethrough);
 
                 debug_log!("Strikethrough");
             }
-            Tag::Link { dest_url, .. } => {
-                assert!(self.link_dest_url.is_empty());
+            Tag::Link {
+                link_type,
+                dest_url,
+                ..
+            } => {
+                if self.link_label.is_some() {
+                    return Err(anyhow!("Nested links"));
+                }
 
-                self.link_dest_url = dest_url.to_string();
+                self.link_label = Some(LinkLabel::default());
+                // Email autolinks like `<me@example.com>` come without a scheme.
+                self.link_dest_url = match link_type {
+                    LinkType::Email => format!("mailto:{dest_url}"),
+                    _ => dest_url.to_string(),
+                };
 
                 debug_log!("Link");
             }
             Tag::Image { dest_url, .. } => {
                 // Render images as a simple link placeholder: [Image](url)
-                let mut link = String::new();
-                link.push_str("[Image](");
-                push_escaped(&mut link, &dest_url);
-                link.push(')');
-                self.write(&link, false, false, false);
+                if self.config.drop_images {
+                    // The text after the image brings its own space, if any.
+                    self.trim_trailing_ws();
+                } else if let Some(label) = self.link_label.as_mut() {
+                    // Links can't nest, so an image inside a link label only
+                    // contributes its placeholder text to that label.
+                    label.push_text(&self.config.image_fallback_label, &self.config);
+                } else if self.config.image_fallback_label.trim().is_empty() {
+                    let url = self.rewrite_url(&dest_url).into_owned();
+                    self.output(&url, true);
+                } else {
+                    let mut label = LinkLabel::default();
+                    label.push_text(&self.config.image_fallback_label, &self.config);
+                    self.write_link(&label, &dest_url);
+                }
 
                 // Skip any nested alt-text events until the matching end tag to
                 // avoid emitting the alt content (Telegram won't render it).
+                // Formatting inside the alt text is skipped along with it, so
+                // no entity gets opened inside the placeholder label.
                 self.skip_depth = 1;
 
                 debug_log!("Image");
@@ -727,6 +2568,17 @@ impl Converter {
                 debug_log!("DefinitionList");
             }
             Tag::DefinitionListTitle => {
+                // Terms are bold lines with their definitions below them.
+                if self.after_list_prefix {
+                    self.after_list_prefix = false;
+                } else {
+                    self.new_line();
+                }
+                self.count_entity();
+                self.ensure_space(SpaceBudget::for_open(1, 1, 1));
+                self.output("*", false);
+                self.stack.push(Descriptor::Strong);
+
                 debug_log!("DefinitionListTitle");
             }
             Tag::DefinitionListDefinition => {
@@ -745,10 +2597,23 @@ impl Converter {
                 debug_log!("EndParagraph");
             }
             TagEnd::Heading(level) => {
-                self.output_closing(heading_closer(level), false);
-                self.add_new_line = false;
-                self.after_heading = true;
+                let dropped = self.drop_empty_opener(heading_prefix(level, &self.config));
+                if dropped {
+                    // Nothing is left of the heading, not even its line.
+                    self.trim_trailing_blank_lines();
+                    self.add_new_line = true;
+                } else {
+                    self.output_closing(heading_closer(level, &self.config), false);
+                    self.add_new_line = false;
+                    self.after_heading = true;
+                }
                 self.close_descriptor(Descriptor::Heading(level))?;
+                if !dropped
+                    && self.config.heading_style == HeadingStyle::Underlined
+                    && !is_flattened(level, &self.config)
+                {
+                    self.write_heading_underline();
+                }
                 self.heading_body_written = false;
 
                 debug_log!("EndHeading");
@@ -767,6 +2632,8 @@ impl Converter {
                 debug_log!("EndCodeBlock");
             }
             TagEnd::HtmlBlock => {
+                // A comment left open ends with its block.
+                self.in_html_comment = false;
                 debug_log!("EndHtmlBlock");
             }
             TagEnd::List(_) => {
@@ -785,12 +2652,22 @@ impl Converter {
                 debug_log!("EndList");
             }
             TagEnd::Item => {
+                // An empty item keeps just its marker, without the space.
+                self.trim_bare_list_prefix();
+                if let Some(state) = self.list_stack.last_mut() {
+                    state.item_width = None;
+                }
+
                 debug_log!("EndItem");
             }
             TagEnd::FootnoteDefinition => {
                 debug_log!("EndFootnoteDefinition");
             }
             TagEnd::Table => {
+                if let Some(table) = self.table.take() {
+                    self.render_table(table)?;
+                }
+
                 debug_log!("EndTable");
             }
             TagEnd::TableHead => {
@@ -808,23 +2685,48 @@ impl Converter {
             TagEnd::Superscript => {
                 debug_log!("EndSuperscript");
             }
+            TagEnd::Emphasis if self.stack.last() == Some(&Descriptor::Plain) => {
+                self.close_descriptor(Descriptor::Plain)?;
+            }
             TagEnd::Emphasis => {
-                self.output_closing("_", false);
+                if !self.drop_empty_opener("_") {
+                    self.output_closing("_", false);
+                }
                 self.close_descriptor(Descriptor::Emphasis)?;
 
                 debug_log!("EndEmphasis");
             }
+            TagEnd::Strong if self.stack.last() == Some(&Descriptor::Plain) => {
+                self.close_descriptor(Descriptor::Plain)?;
+            }
             TagEnd::Strong => {
-                self.output_closing("*", false);
+                if !self.drop_empty_opener("*") {
+                    self.output_closing("*", false);
+                }
                 self.close_descriptor(Descriptor::Strong)?;
 
                 debug_log!("EndStrong");
             }
+            TagEnd::Strikethrough if self.stack.last() == Some(&Descriptor::Plain) => {
+                self.close_descriptor(Descriptor::Plain)?;
+            }
             TagEnd::Strikethrough => {
-                self.output_closing("~~", false);
+                if !self.drop_empty_opener("~") {
+                    self.output_closing("~", false);
+                }
                 self.close_descriptor(Descriptor::Strikethrough)?;
             }
             TagEnd::Link => {
+                let mut label = self.link_label.take().unwrap_or_default();
+                let url = std::mem::take(&mut self.link_dest_url);
+                // A label with nothing visible leaves nothing to tap, so the
+                // URL stands in for it.
+                if label.text.trim().is_empty() {
+                    label = LinkLabel::default();
+                    label.push_text(url.strip_prefix("mailto:").unwrap_or(&url), &self.config);
+                }
+                self.write_link(&label, &url);
+
                 debug_log!("EndLink");
             }
             TagEnd::Image => {
@@ -834,12 +2736,20 @@ impl Converter {
                 debug_log!("EndMetadataBlock");
             }
             TagEnd::DefinitionList => {
+                self.add_new_line = true;
+
                 debug_log!("EndDefinitionList");
             }
             TagEnd::DefinitionListTitle => {
+                self.output_closing("*", false);
+                self.close_descriptor(Descriptor::Strong)?;
+                self.add_new_line = true;
+
                 debug_log!("EndDefinitionListTitle");
             }
             TagEnd::DefinitionListDefinition => {
+                self.add_new_line = true;
+
                 debug_log!("EndDefinitionListDefinition");
             }
         }
@@ -847,14 +2757,197 @@ impl Converter {
         Ok(())
     }
 
+    fn render_table(&mut self, table: TableState) -> anyhow::Result<()> {
+        match self.config.table_mode {
+            TableMode::KeyValue => self.render_table_key_value(&table)?,
+            TableMode::CodeBlock => self.render_table_code_block(&table)?,
+        }
+        self.add_new_line = true;
+
+        Ok(())
+    }
+
+    /// One line per data row: `*header*: value · *header*: value`.
+    fn render_table_key_value(&mut self, table: &TableState) -> anyhow::Result<()> {
+        self.new_line();
+        for (row_idx, row) in table.rows.iter().enumerate() {
+            if row_idx > 0 {
+                self.add_new_line = true;
+            }
+            let mut first = true;
+            for (idx, value) in row.iter().enumerate() {
+                let header = table.head.get(idx).map(String::as_str).unwrap_or("");
+                if header.is_empty() && value.is_empty() {
+                    continue;
+                }
+                // Not `|`, which would look like a literal pipe in a cell.
+                if !first {
+                    self.output(" · ", true);
+                }
+                first = false;
+                if !header.is_empty() {
+                    self.start_tag(Tag::Strong)?;
+                    self.output(header, true);
+                    self.end_tag(TagEnd::Strong)?;
+                    if !value.is_empty() {
+                        self.output(": ", true);
+                    }
+                }
+                self.output(value, true);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Draw the table as a padded `| a | b |` grid inside a code block.
+    fn render_table_code_block(&mut self, table: &TableState) -> anyhow::Result<()> {
+        // Literal pipes would read as column delimiters in the grid, so they
+        // keep the backslash they had in the Markdown source.
+        let grid_row = |row: &Vec<String>| -> Vec<String> {
+            row.iter().map(|cell| cell.replace('|', "\\|")).collect()
+        };
+        let head = grid_row(&table.head);
+        let rows: Vec<Vec<String>> = table.rows.iter().map(grid_row).collect();
+
+        let columns = rows
+            .iter()
+            .map(Vec::len)
+            .chain(std::iter::once(head.len()))
+            .max()
+            .unwrap_or(0);
+        let mut widths = vec![0; columns];
+        for row in std::iter::once(&head).chain(rows.iter()) {
+            for (idx, cell) in row.iter().enumerate() {
+                widths[idx] = widths[idx].max(cell.chars().count());
+            }
+        }
+
+        self.start_tag(Tag::CodeBlock(CodeBlockKind::Indented))?;
+        let mut lines = vec![grid_line(&head, &widths)];
+        lines.push(grid_separator(&widths));
+        lines.extend(rows.iter().map(|row| grid_line(row, &widths)));
+        for line in lines {
+            self.output(&line, true);
+            self.add_new_line = true;
+        }
+        self.end_tag(TagEnd::CodeBlock)?;
+
+        Ok(())
+    }
+
     fn close_descriptor(&mut self, descriptor: Descriptor) -> anyhow::Result<()> {
-        let last = self.stack.pop().expect("Unexpected end tag");
-        assert_eq!(last, descriptor, "Unexpected end tag");
+        match self.stack.pop() {
+            Some(last) if last == descriptor => {}
+            _ => return Err(anyhow!("Unexpected end tag")),
+        }
 
         Ok(())
     }
 }
 
+/// Convert `markdown` with the default configuration, into chunks that fit
+/// a Telegram message. Short for `Converter::default().go(markdown)`.
+pub fn chunks(markdown: &str) -> anyhow::Result<Vec<String>> {
+    Converter::default().go(markdown)
+}
+
+/// Converts a queue of documents with one shared configuration, reusing the
+/// same [`Converter`] between documents.
+#[derive(Debug, Default)]
+pub struct BatchConverter {
+    converter: Converter,
+}
+
+impl BatchConverter {
+    pub fn new(config: ConverterConfig) -> Self {
+        Self {
+            converter: Converter::with_config(config),
+        }
+    }
+
+    pub fn config(&self) -> &ConverterConfig {
+        &self.converter.config
+    }
+
+    /// Convert a single document; state from previous documents never leaks.
+    pub fn convert(&mut self, markdown: &str) -> anyhow::Result<Vec<String>> {
+        self.converter.go(markdown)
+    }
+
+    /// Convert every document in order, returning the chunks of each.
+    pub fn convert_all<'a, I>(&mut self, documents: I) -> anyhow::Result<Vec<Vec<String>>>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        documents
+            .into_iter()
+            .map(|markdown| self.convert(markdown))
+            .collect()
+    }
+}
+
+/// Largest char boundary of `text` at or before byte `idx`. Stands in for
+/// `str::floor_char_boundary`, which needs Rust 1.91.
+fn floor_char_boundary(text: &str, idx: usize) -> usize {
+    let mut idx = idx.min(text.len());
+    while !text.is_char_boundary(idx) {
+        idx -= 1;
+    }
+    idx
+}
+
+/// Largest split of `text` at most `fit` bytes long that doesn't separate an
+/// escaping backslash from the character it escapes, nor break up a
+/// character cluster unless the cluster alone is longer than `fit`.
+fn hard_split_len(text: &str, fit: usize) -> usize {
+    let mut len = fit;
+    while len > 0 && splits_cluster(text, len) {
+        len = floor_char_boundary(text, len - 1);
+    }
+    if len == 0 {
+        len = fit;
+    }
+    let backslashes = text[..len]
+        .bytes()
+        .rev()
+        .take_while(|&b| b == b'\\')
+        .count();
+    len - backslashes % 2
+}
+
+/// Whether splitting `text` at byte `idx` would cut through what reads as a
+/// single character: a base and its combining marks, variation selectors or
+/// skin tones, an emoji joined with a ZWJ, or a flag.
+fn splits_cluster(text: &str, idx: usize) -> bool {
+    let (before, after) = text.split_at(idx);
+    let Some(next) = after.chars().next() else {
+        return false;
+    };
+    let is_regional = |ch: char| matches!(ch, '\u{1f1e6}'..='\u{1f1ff}');
+    let regional_before = before
+        .chars()
+        .rev()
+        .take_while(|&ch| is_regional(ch))
+        .count();
+    before.ends_with('\u{200d}')
+        || (is_regional(next) && regional_before % 2 == 1)
+        || matches!(
+            next,
+            '\u{200d}'
+                | '\u{300}'..='\u{36f}'
+                | '\u{1ab0}'..='\u{1aff}'
+                | '\u{1dc0}'..='\u{1dff}'
+                | '\u{20d0}'..='\u{20ff}'
+                | '\u{fe00}'..='\u{fe0f}'
+                | '\u{fe20}'..='\u{fe2f}'
+                | '\u{1f3fb}'..='\u{1f3ff}'
+                | '\u{e0020}'..='\u{e007f}'
+        )
+}
+
+/// Byte index at which to split `text` so the first part is at most `max_len`
+/// bytes, preferring whitespace. `max_len` must lie on a char boundary.
 fn split_point(text: &str, max_len: usize, allow_hard_split: bool) -> usize {
     if text.len() <= max_len {
         return text.len();
@@ -862,11 +2955,17 @@ fn split_point(text: &str, max_len: usize, allow_hard_split: bool) -> usize {
 
     let mut last_space = None;
     for (idx, ch) in text.char_indices() {
-        if idx >= max_len {
+        let end = idx + ch.len_utf8();
+        if end > max_len {
+            // A space right after a word that exactly fills the limit still
+            // ends the chunk; the space itself is trimmed by the caller.
+            if idx == max_len && matches!(ch, ' ' | '\t') {
+                last_space = Some(idx);
+            }
             break;
         }
-        if ch.is_whitespace() {
-            last_space = Some(idx + ch.len_utf8());
+        if (is_split_space(ch) || ch == SOFT_HYPHEN) && !splits_cluster(text, end) {
+            last_space = Some(end);
         }
     }
 
@@ -877,55 +2976,175 @@ fn split_point(text: &str, max_len: usize, allow_hard_split: bool) -> usize {
     if allow_hard_split { max_len } else { 0 }
 }
 
-fn descriptor_closer(desc: &Descriptor) -> &'static str {
+/// Like [`split_point`], but for code: prefers the end of the last whole
+/// line, so no line of code is broken apart or loses its trailing spaces.
+fn code_split_point(text: &str, max_len: usize) -> usize {
+    if text.len() <= max_len {
+        return text.len();
+    }
+    match text[..max_len].rfind('\n') {
+        Some(idx) => idx + 1,
+        None => split_point(text, max_len, false),
+    }
+}
+
+/// Length of the GFM autolink literal (`www.`, `http://` or `https://` URL)
+/// at the start of `text`, leaving out trailing punctuation and unbalanced
+/// closing parentheses.
+fn autolink_literal_len(text: &str) -> Option<usize> {
+    let prefix = ["https://", "http://", "www."]
+        .into_iter()
+        .find(|prefix| text.starts_with(prefix))?;
+    let mut len = text
+        .find(|c: char| c.is_whitespace() || c == '<')
+        .unwrap_or(text.len());
+    while len > prefix.len() {
+        let link = &text[..len];
+        let unbalanced_paren =
+            link.ends_with(')') && link.matches(')').count() > link.matches('(').count();
+        if link.ends_with(['?', '!', '.', ',', ':', ';', '*', '_', '~', '\'', '"'])
+            || unbalanced_paren
+        {
+            len -= 1;
+        } else {
+            break;
+        }
+    }
+
+    // The domain needs a period; `www.` already brings one.
+    let domain = text[prefix.len()..len].split(['/', '?', '#']).next()?;
+    let valid = !domain.is_empty() && (prefix == "www." || domain.contains('.'));
+    valid.then_some(len)
+}
+
+/// Leading part of `text` up to the first place it may be split.
+fn first_word(text: &str) -> &str {
+    let end = text
+        .char_indices()
+        .find_map(|(idx, ch)| {
+            let end = idx + ch.len_utf8();
+            if ch == SOFT_HYPHEN {
+                Some(end)
+            } else {
+                (is_split_space(ch) && !splits_cluster(text, end)).then_some(idx)
+            }
+        })
+        .unwrap_or(text.len());
+    &text[..end]
+}
+
+/// `text` without its soft hyphens, except one it ends with when a word is
+/// broken there.
+fn without_soft_hyphens(text: &str, at_break: bool) -> Cow<'_, str> {
+    if !text.contains(SOFT_HYPHEN) {
+        return Cow::Borrowed(text);
+    }
+    let (body, hyphen) = match text.strip_suffix(SOFT_HYPHEN) {
+        Some(body) if at_break => (body, Some(SOFT_HYPHEN)),
+        _ => (text, None),
+    };
+    Cow::Owned(
+        body.chars()
+            .filter(|&ch| ch != SOFT_HYPHEN)
+            .chain(hyphen)
+            .collect(),
+    )
+}
+
+/// Whitespace that may end a chunk. Non-breaking spaces are meant to keep
+/// their neighbours together, so they never do.
+fn is_split_space(ch: char) -> bool {
+    ch.is_whitespace() && !matches!(ch, '\u{a0}' | '\u{2007}' | '\u{202f}')
+}
+
+fn grid_line(cells: &[String], widths: &[usize]) -> String {
+    let mut line = String::from("|");
+    for (idx, width) in widths.iter().enumerate() {
+        let cell = cells.get(idx).map(String::as_str).unwrap_or("");
+        let pad = width - cell.chars().count();
+        line.push(' ');
+        line.push_str(cell);
+        line.push_str(&" ".repeat(pad));
+        line.push_str(" |");
+    }
+    line
+}
+
+fn grid_separator(widths: &[usize]) -> String {
+    let mut line = String::from("|");
+    for width in widths {
+        line.push_str(&"-".repeat(width + 2));
+        line.push('|');
+    }
+    line
+}
+
+fn descriptor_closer(desc: &Descriptor, config: &ConverterConfig) -> &'static str {
     match desc {
         Descriptor::Strong => "*",
         Descriptor::Emphasis => "_",
-        Descriptor::Strikethrough => "~~",
+        Descriptor::Strikethrough => "~",
         Descriptor::Code => "`",
         Descriptor::CodeBlock(_) => "```",
-        Descriptor::Heading(level) => heading_closer(*level),
+        Descriptor::Heading(level) => heading_closer(*level, config),
+        Descriptor::Underline => "__",
+        Descriptor::Spoiler => "||",
+        Descriptor::ExpandableQuote => "||",
+        Descriptor::Plain => "",
     }
 }
 
-fn heading_prefix(level: HeadingLevel) -> &'static str {
-    match level {
-        HeadingLevel::H1 => "*🌟 ",
-        HeadingLevel::H2 => "*⭐ ",
-        HeadingLevel::H3 => "*✨ ",
-        HeadingLevel::H4 => "*🔸 ",
-        HeadingLevel::H5 => "_🔹 ",
-        HeadingLevel::H6 => "_✴️ ",
+/// Whether a heading is deeper than `max_heading_level` and so rendered as
+/// plain bold text.
+fn is_flattened(level: HeadingLevel, config: &ConverterConfig) -> bool {
+    level as u8 > config.max_heading_level
+}
+
+fn heading_prefix(level: HeadingLevel, config: &ConverterConfig) -> &'static str {
+    if is_flattened(level, config) {
+        return "*";
+    }
+    if config.dialect == Dialect::MarkdownV1 && config.heading_style == HeadingStyle::Hashes {
+        // `#` can't be escaped in legacy Markdown, nor does it need to be.
+        return match level {
+            HeadingLevel::H1 => "*# ",
+            HeadingLevel::H2 => "*## ",
+            HeadingLevel::H3 => "*### ",
+            HeadingLevel::H4 => "*#### ",
+            HeadingLevel::H5 => "_##### ",
+            HeadingLevel::H6 => "_###### ",
+        };
+    }
+    if config.strip_emoji && config.heading_style == HeadingStyle::Emoji {
+        return heading_closer(level, config);
+    }
+    match (config.heading_style, level) {
+        (HeadingStyle::Emoji, HeadingLevel::H1) => "*🌟 ",
+        (HeadingStyle::Emoji, HeadingLevel::H2) => "*⭐ ",
+        (HeadingStyle::Emoji, HeadingLevel::H3) => "*✨ ",
+        (HeadingStyle::Emoji, HeadingLevel::H4) => "*🔸 ",
+        (HeadingStyle::Emoji, HeadingLevel::H5) => "_🔹 ",
+        (HeadingStyle::Emoji, HeadingLevel::H6) => "_✴️ ",
+        (HeadingStyle::Hashes, HeadingLevel::H1) => "*\\# ",
+        (HeadingStyle::Hashes, HeadingLevel::H2) => "*\\#\\# ",
+        (HeadingStyle::Hashes, HeadingLevel::H3) => "*\\#\\#\\# ",
+        (HeadingStyle::Hashes, HeadingLevel::H4) => "*\\#\\#\\#\\# ",
+        (HeadingStyle::Hashes, HeadingLevel::H5) => "_\\#\\#\\#\\#\\# ",
+        (HeadingStyle::Hashes, HeadingLevel::H6) => WIDEST_HEADING_PREFIX,
+        (HeadingStyle::Underlined | HeadingStyle::Plain, _) => heading_closer(level, config),
     }
 }
 
-fn heading_closer(level: HeadingLevel) -> &'static str {
+fn heading_closer(level: HeadingLevel, config: &ConverterConfig) -> &'static str {
+    if is_flattened(level, config) {
+        return "*";
+    }
     match level {
         HeadingLevel::H1 | HeadingLevel::H2 | HeadingLevel::H3 | HeadingLevel::H4 => "*",
         HeadingLevel::H5 | HeadingLevel::H6 => "_",
     }
 }
 
-/// Escape Telegram MarkdownV2 control characters into the provided buffer.
-fn push_escaped(out: &mut String, text: &str) {
-    for ch in text.chars() {
-        match ch {
-            '\\' | '*' | '_' | '[' | ']' | '(' | ')' | '~' | '`' | '>' | '#' | '+' | '-' | '='
-            | '|' | '{' | '}' | '.' | '!' => {
-                out.push('\\');
-                out.push(ch);
-            }
-            _ => out.push(ch),
-        }
-    }
-}
-
-fn escape_str(text: &str) -> String {
-    let mut out = String::with_capacity(text.len());
-    push_escaped(&mut out, text);
-    out
-}
-
 impl PartialEq for Descriptor {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
@@ -935,7 +3154,11 @@ impl PartialEq for Descriptor {
             (Descriptor::Code, Descriptor::Code) => true,
             (Descriptor::Strikethrough, Descriptor::Strikethrough) => true,
             (Descriptor::Heading(a), Descriptor::Heading(b)) => a == b,
-            _ => unimplemented!(),
+            (Descriptor::Underline, Descriptor::Underline) => true,
+            (Descriptor::Spoiler, Descriptor::Spoiler) => true,
+            (Descriptor::ExpandableQuote, Descriptor::ExpandableQuote) => true,
+            (Descriptor::Plain, Descriptor::Plain) => true,
+            _ => false,
         }
     }
 }
@@ -948,6 +3171,8 @@ struct ListState {
     start: u64,
     items: usize,
     extra_levels: usize,
+    // Visible width of the open item's prefix, used to indent its continuation lines.
+    item_width: Option<usize>,
 }
 
 impl ListState {
@@ -957,6 +3182,174 @@ impl ListState {
             start: n.unwrap_or(1),
             items: 0,
             extra_levels,
+            item_width: None,
+        }
+    }
+
+    /// Number of the next item, or of the open one once it is counted.
+    /// Saturates instead of overflowing on lists that start near the limit.
+    fn number(&self) -> u64 {
+        self.start.saturating_add(self.items as u64)
+    }
+}
+
+/// Tag of a `<details>` block, the only HTML that gets rendered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DetailsTag<'a> {
+    Open,
+    Summary(&'a str),
+    Close,
+}
+
+/// Names of the entities in `stack` for debugging, leaving out formatting
+/// written as plain text.
+fn entity_names(stack: &[Descriptor]) -> Vec<String> {
+    stack
+        .iter()
+        .filter(|desc| **desc != Descriptor::Plain)
+        .map(|desc| format!("{desc:?}"))
+        .collect()
+}
+
+/// Title line of a GitHub alert quote.
+fn alert_title(kind: BlockQuoteKind) -> &'static str {
+    match kind {
+        BlockQuoteKind::Note => "ℹ️ Note",
+        BlockQuoteKind::Tip => "💡 Tip",
+        BlockQuoteKind::Important => "❗ Important",
+        BlockQuoteKind::Warning => "⚠️ Warning",
+        BlockQuoteKind::Caution => "🛑 Caution",
+    }
+}
+
+/// Whether `line` holds nothing but quote markers and spaces.
+fn is_blank_line(line: &str, quote_prefix: &str) -> bool {
+    let marker = quote_prefix.trim_matches(' ');
+    line.split(' ')
+        .all(|part| part.trim_start_matches(marker).is_empty())
+}
+
+/// Whether `html` is a `<br>` tag, in any case and with or without `/`.
+fn is_line_break_tag(html: &str) -> bool {
+    html.strip_prefix('<')
+        .and_then(|tag| tag.strip_suffix('>'))
+        .map(|tag| tag.trim_end().trim_end_matches('/').trim_end())
+        .is_some_and(|name| name.eq_ignore_ascii_case("br"))
+}
+
+/// `html` without its `<!-- ... -->` comments. `in_comment` carries a
+/// comment still open at the end over to the next piece of HTML.
+fn without_html_comments<'a>(html: &'a str, in_comment: &mut bool) -> Cow<'a, str> {
+    if !*in_comment && !html.contains("<!--") {
+        return Cow::Borrowed(html);
+    }
+    let mut out = String::new();
+    let mut rest = html;
+    loop {
+        if *in_comment {
+            let Some(end) = rest.find("-->") else {
+                break;
+            };
+            rest = &rest[end + "-->".len()..];
+            *in_comment = false;
+        } else if let Some(start) = rest.find("<!--") {
+            out.push_str(&rest[..start]);
+            rest = &rest[start + "<!--".len()..];
+            *in_comment = true;
+        } else {
+            out.push_str(rest);
+            break;
+        }
+    }
+    Cow::Owned(out)
+}
+
+/// Split an HTML block made only of `<details>`, `<summary>...</summary>`
+/// and `</details>` tags into those tags. Any other HTML yields `None`.
+fn details_tags(html: &str) -> Option<Vec<DetailsTag<'_>>> {
+    let mut tags = Vec::new();
+    let mut rest = html.trim_start();
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("<summary>") {
+            let end = after.find("</summary>")?;
+            tags.push(DetailsTag::Summary(after[..end].trim()));
+            rest = &after[end + "</summary>".len()..];
+        } else if let Some(after) = rest.strip_prefix("</details>") {
+            tags.push(DetailsTag::Close);
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("<details")
+            && after.starts_with(|c: char| c == '>' || c.is_whitespace())
+        {
+            // Attributes such as `open` don't matter to Telegram.
+            tags.push(DetailsTag::Open);
+            rest = &after[after.find('>')? + 1..];
+        } else {
+            return None;
+        }
+        rest = rest.trim_start();
+    }
+    Some(tags)
+}
+
+/// Link label as plain text, for the `label (url)` fallback, and as escaped
+/// MarkdownV2 for the `[label](url)` markup.
+#[derive(Debug, Default)]
+struct LinkLabel {
+    text: String,
+    markup: String,
+}
+
+impl LinkLabel {
+    fn push_text(&mut self, text: &str, config: &ConverterConfig) {
+        self.text.push_str(text);
+        // Legacy Markdown takes link text literally.
+        let context = match config.dialect {
+            Dialect::MarkdownV2 => EntityContext::Text,
+            Dialect::MarkdownV1 => EntityContext::Code,
+        };
+        push_escaped_in(&mut self.markup, text, context, config);
+    }
+
+    /// Inline code keeps its backticks inside the label, where the dialect
+    /// allows nested entities.
+    fn push_code(&mut self, code: &str, config: &ConverterConfig) {
+        if config.dialect == Dialect::MarkdownV1 {
+            self.push_text(code, config);
+            return;
+        }
+        self.text.push_str(code);
+        self.markup.push('`');
+        push_code_content(&mut self.markup, code, config);
+        self.markup.push('`');
+    }
+}
+
+/// Plain-text cells of a table collected until its end tag.
+#[derive(Debug, Default)]
+struct TableState {
+    head: Vec<String>,
+    rows: Vec<Vec<String>>,
+    row: Vec<String>,
+    cell: String,
+}
+
+impl TableState {
+    /// Consume an event that belongs to the table body. Returns `false` only
+    /// for the table end tag, which the converter renders itself. Inline
+    /// formatting inside cells is flattened to plain text and paragraphs are
+    /// joined with a space, so a cell never spans more than one grid line.
+    fn capture(&mut self, event: &Event) -> bool {
+        match event {
+            Event::End(TagEnd::Table) => return false,
+            Event::Start(Tag::TableCell) => self.cell.clear(),
+            Event::End(TagEnd::TableCell) => self.row.push(std::mem::take(&mut self.cell)),
+            Event::End(TagEnd::TableHead) => self.head = std::mem::take(&mut self.row),
+            Event::End(TagEnd::TableRow) => self.rows.push(std::mem::take(&mut self.row)),
+            Event::Text(txt) | Event::Code(txt) => self.cell.push_str(txt),
+            Event::SoftBreak | Event::HardBreak => self.cell.push(' '),
+            Event::Start(Tag::Paragraph) if !self.cell.is_empty() => self.cell.push(' '),
+            _ => {}
         }
+        true
     }
 }
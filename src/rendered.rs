@@ -0,0 +1,238 @@
+//! Tokenizer and checker for text that is already Telegram MarkdownV2.
+
+use anyhow::anyhow;
+
+use crate::escape::needs_escape;
+
+/// Entity whose opening and closing markers are the same string.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Marker {
+    Bold,
+    Italic,
+    Underline,
+    Strikethrough,
+    Spoiler,
+}
+
+/// A piece of rendered MarkdownV2. Text is kept exactly as written, escapes
+/// included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Token<'a> {
+    /// Escaped prose, possibly spanning several lines.
+    Text(&'a str),
+    /// Opens the entity, or closes it when it is the innermost open one.
+    Marker(Marker),
+    /// Body of an inline code entity.
+    Code(&'a str),
+    /// A code block: the language and the body between the fences.
+    Pre { lang: &'a str, body: &'a str },
+    /// A whole `[label](url)` link or custom emoji.
+    Link(&'a str),
+    /// The `**` before the first `>` of an expandable quote.
+    ExpandableQuote,
+    /// The `||` that ends the last line of an expandable quote.
+    ExpandableQuoteEnd,
+}
+
+/// Split `text` into tokens. Fails on unterminated code entities.
+pub(crate) fn tokenize(text: &str) -> anyhow::Result<Vec<Token<'_>>> {
+    let mut tokens = Vec::new();
+    let mut text_start = 0;
+    let mut idx = 0;
+    let mut expandable = false;
+    while idx < text.len() {
+        let rest = &text[idx..];
+        let line_start = idx == 0 || text[..idx].ends_with('\n');
+        if line_start {
+            expandable &= rest.starts_with('>');
+        }
+        let (token, len) = if line_start && rest.starts_with("**>") {
+            expandable = true;
+            (Token::ExpandableQuote, 2)
+        } else if expandable && rest.starts_with("||") && ends_quote(&rest[2..]) {
+            expandable = false;
+            (Token::ExpandableQuoteEnd, 2)
+        } else if let Some(escaped) = rest.strip_prefix('\\') {
+            idx += 1 + escaped.chars().next().map_or(0, char::len_utf8);
+            continue;
+        } else if let Some(after) = rest.strip_prefix("```") {
+            let end = find_unescaped(after, "```")
+                .ok_or_else(|| anyhow!("unterminated code block at byte {idx}"))?;
+            let (lang, body) = match after[..end].split_once('\n') {
+                Some((lang, body)) => (lang, body),
+                None => ("", &after[..end]),
+            };
+            (Token::Pre { lang, body }, 3 + end + 3)
+        } else if let Some(after) = rest.strip_prefix('`') {
+            let end = find_unescaped(after, "`")
+                .ok_or_else(|| anyhow!("unterminated inline code at byte {idx}"))?;
+            (Token::Code(&after[..end]), 1 + end + 1)
+        } else if rest.starts_with("__") {
+            (Token::Marker(Marker::Underline), 2)
+        } else if rest.starts_with("||") {
+            (Token::Marker(Marker::Spoiler), 2)
+        } else if rest.starts_with('_') {
+            (Token::Marker(Marker::Italic), 1)
+        } else if rest.starts_with('*') {
+            (Token::Marker(Marker::Bold), 1)
+        } else if rest.starts_with('~') {
+            (Token::Marker(Marker::Strikethrough), 1)
+        } else if let Some(len) = link_len(rest) {
+            (Token::Link(&rest[..len]), len)
+        } else {
+            idx += rest.chars().next().map_or(1, char::len_utf8);
+            continue;
+        };
+
+        if text_start < idx {
+            tokens.push(Token::Text(&text[text_start..idx]));
+        }
+        tokens.push(token);
+        idx += len;
+        text_start = idx;
+    }
+    if text_start < text.len() {
+        tokens.push(Token::Text(&text[text_start..]));
+    }
+
+    Ok(tokens)
+}
+
+/// Check that `text` is MarkdownV2 Telegram accepts: every entity closed in
+/// the order it was opened and no reserved character left unescaped outside
+/// code and links. A `#` may stay bare when `allow_hashtags` is set.
+pub(crate) fn validate(text: &str, allow_hashtags: bool) -> anyhow::Result<()> {
+    let text = strip_expandable_quote_marks(text);
+    let mut open: Vec<Marker> = Vec::new();
+    for token in tokenize(&text)? {
+        match token {
+            Token::Text(prose) => {
+                let offset = prose.as_ptr() as usize - text.as_ptr() as usize;
+                let mut chars = prose.char_indices();
+                while let Some((idx, ch)) = chars.next() {
+                    if ch == '\\' {
+                        chars.next();
+                        continue;
+                    }
+                    let quote_mark = ch == '>'
+                        && text[..offset + idx]
+                            .rsplit('\n')
+                            .next()
+                            .is_some_and(|line| line.chars().all(|c| c == '>'));
+                    if needs_escape(ch) && !quote_mark && !(ch == '#' && allow_hashtags) {
+                        return Err(anyhow!("unescaped '{ch}' at byte {}", offset + idx));
+                    }
+                }
+            }
+            Token::Marker(marker) => {
+                if open.last() == Some(&marker) {
+                    open.pop();
+                } else if open.contains(&marker) {
+                    return Err(anyhow!("{marker:?} closed before the entities inside it"));
+                } else {
+                    open.push(marker);
+                }
+            }
+            Token::Code(_) | Token::Pre { .. } | Token::Link(_) => {}
+            // Removed along with the expandability marks above.
+            Token::ExpandableQuote | Token::ExpandableQuoteEnd => {}
+        }
+    }
+    match open.last() {
+        Some(marker) => Err(anyhow!("{marker:?} is never closed")),
+        None => Ok(()),
+    }
+}
+
+/// Number of entities in `text`, which must be valid MarkdownV2. Every run
+/// of quoted lines is one quote.
+pub(crate) fn count_entities(text: &str) -> anyhow::Result<usize> {
+    let text = strip_expandable_quote_marks(text);
+    let mut count = usize::from(text.starts_with('>'));
+    let mut quoted = count == 1;
+    let mut open: Vec<Marker> = Vec::new();
+    for token in tokenize(&text)? {
+        match token {
+            Token::Text(prose) => {
+                let offset = prose.as_ptr() as usize - text.as_ptr() as usize;
+                for (idx, _) in prose.match_indices('\n') {
+                    let line_quoted = text[offset + idx + 1..].starts_with('>');
+                    count += usize::from(line_quoted && !quoted);
+                    quoted = line_quoted;
+                }
+            }
+            Token::Marker(marker) => {
+                if open.last() == Some(&marker) {
+                    open.pop();
+                } else {
+                    open.push(marker);
+                    count += 1;
+                }
+            }
+            Token::Code(_) | Token::Pre { .. } | Token::Link(_) => count += 1,
+            Token::ExpandableQuote | Token::ExpandableQuoteEnd => {}
+        }
+    }
+    Ok(count)
+}
+
+/// `text` with the `**` that starts an expandable quote and the `||` that
+/// ends it on its last line removed, leaving a regular quote.
+fn strip_expandable_quote_marks(text: &str) -> String {
+    let lines: Vec<&str> = text.split('\n').collect();
+    let mut expandable = false;
+    let mut out = Vec::with_capacity(lines.len());
+    for (idx, line) in lines.iter().enumerate() {
+        let mut line = *line;
+        if line.starts_with("**>") {
+            line = &line[2..];
+            expandable = true;
+        }
+        let quote_ends = !lines.get(idx + 1).is_some_and(|next| next.starts_with('>'));
+        if expandable && quote_ends {
+            expandable = false;
+            if !line.ends_with("\\||") {
+                line = line.strip_suffix("||").unwrap_or(line);
+            }
+        }
+        out.push(line);
+    }
+    out.join("\n")
+}
+
+/// Whether `rest`, what follows a `||`, ends the quote: the text or the
+/// line ends and no quoted line follows.
+fn ends_quote(rest: &str) -> bool {
+    match rest.strip_prefix('\n') {
+        Some(next) => !next.starts_with('>'),
+        None => rest.is_empty(),
+    }
+}
+
+/// Byte index of the first `needle` in `text` that isn't preceded by an
+/// escaping backslash.
+fn find_unescaped(text: &str, needle: &str) -> Option<usize> {
+    let mut idx = 0;
+    while idx < text.len() {
+        let rest = &text[idx..];
+        if let Some(escaped) = rest.strip_prefix('\\') {
+            idx += 1 + escaped.chars().next().map_or(0, char::len_utf8);
+        } else if rest.starts_with(needle) {
+            return Some(idx);
+        } else {
+            idx += rest.chars().next().map_or(1, char::len_utf8);
+        }
+    }
+    None
+}
+
+/// Length of the `[label](url)` or `![emoji](url)` link at the start of
+/// `text`, if there is one.
+fn link_len(text: &str) -> Option<usize> {
+    let lead = usize::from(text.starts_with('!'));
+    let label = text[lead..].strip_prefix('[')?;
+    let label_end = find_unescaped(label, "](")?;
+    let url = &label[label_end + 2..];
+    let url_end = find_unescaped(url, ")")?;
+    Some(lead + 1 + label_end + 2 + url_end + 1)
+}
@@ -1,4 +1,8 @@
-use md2tgmdv2::Converter;
+use md2tgmdv2::{
+    BatchConverter, ChunkStrategy, ChunkTrim, Converter, ConverterConfig, Dialect, EntityContext,
+    Error, EscapeMode, HeadingStyle, LengthMetric, MIN_MAX_LEN, OverflowStrategy, TableMode,
+    UrlRewriter, chunks, escape_for, utf16_len,
+};
 
 fn transform_expect_1(input: &str, expected: &str) {
     let chunks = Converter::default().go(input).unwrap();
@@ -9,6 +13,21 @@ fn transform_expect_1(input: &str, expected: &str) {
     assert_eq!(chunks[0], expected);
 }
 
+/// Like `transform_expect_1`, with task lists, definition lists and alerts
+/// parsed.
+fn transform_expect_gfm(input: &str, expected: &str) {
+    let chunks = Converter::with_config(ConverterConfig {
+        enable_task_lists: true,
+        enable_definition_lists: true,
+        enable_alerts: true,
+        ..Default::default()
+    })
+    .go(input)
+    .unwrap();
+
+    assert_eq!(chunks, vec![expected]);
+}
+
 #[allow(dead_code)]
 fn transform_expect_n(input: &str, expected: &str, max_chunk_length: usize) {
     let chunks = Converter::new(max_chunk_length).go(input).unwrap();
@@ -59,11 +78,96 @@ fn escapes_parentheses_in_text() {
     );
 }
 
+#[test]
+fn preserves_hashtags_when_configured() {
+    let input = "tags #rust and #c_lang, not a# or # alone";
+
+    transform_expect_1(input, "tags \\#rust and \\#c\\_lang, not a\\# or \\# alone");
+
+    let chunks = Converter::with_config(ConverterConfig {
+        preserve_hashtags: true,
+        ..Default::default()
+    })
+    .go(input)
+    .unwrap();
+    assert_eq!(
+        chunks,
+        vec!["tags #rust and #c\\_lang, not a\\# or \\# alone"]
+    );
+}
+
+#[test]
+fn keeps_pre_escaped_sequences_when_configured() {
+    let mut converter = Converter::with_config(ConverterConfig {
+        detect_pre_escaped: true,
+        ..Default::default()
+    });
+
+    // CommonMark already resolves backslash escapes in prose.
+    transform_expect_1("1\\. Done \\*now\\*", "1\\. Done \\*now\\*");
+    assert_eq!(
+        converter.go("1\\. Done \\*now\\*").unwrap(),
+        vec!["1\\. Done \\*now\\*"]
+    );
+
+    // Code spans keep backslashes verbatim, so escapes there used to double.
+    transform_expect_1("`a\\.b \\*c`", "`a\\\\.b \\\\*c`");
+    assert_eq!(
+        converter.go("`a\\.b \\*c \\\\.d \\q`").unwrap(),
+        vec!["`a\\.b \\*c \\\\.d \\\\q`"]
+    );
+}
+
+#[test]
+fn keeps_mentions_intact() {
+    // `@` needs no escaping; underscores inside a username must stay escaped
+    // so they don't open italics, and Telegram still detects the mention.
+    transform_expect_1(
+        "ping @somebot, or @some_bot_name.",
+        "ping @somebot, or @some\\_bot\\_name\\.",
+    );
+}
+
+#[test]
+fn compares_conservative_and_minimal_escaping() {
+    let input = "Run `a_b(1).c` now! See [docs](https://x.io/a_(b)).\n\n```py\nf(x) * 2 # `q`\n```";
+    let convert = |escape_mode| {
+        Converter::with_config(ConverterConfig {
+            escape_mode,
+            ..Default::default()
+        })
+        .go(input)
+        .unwrap()
+    };
+
+    assert_eq!(
+        convert(EscapeMode::Conservative),
+        vec![
+            "Run `a_b(1).c` now\\! See [docs](https://x\\.io/a\\_\\(b\\))\\.\n\
+             ```py\nf(x) * 2 # \\`q\\`\n```"
+        ]
+    );
+    assert_eq!(
+        convert(EscapeMode::Minimal),
+        vec![
+            "Run `a_b(1).c` now\\! See [docs](https://x.io/a_(b\\))\\.\n\
+             ```py\nf(x) * 2 # \\`q\\`\n```"
+        ]
+    );
+}
+
 #[test]
 fn escapes_trailing_period_in_line() {
     transform_expect_1("the past.\n", "the past\\.");
 }
 
+#[test]
+fn budgets_tokens_by_their_escaped_length() {
+    // Four source characters, eight once escaped.
+    transform_expect_n("a .... b", "a===\\.\\.\\.\\.===b", 9);
+    transform_expect_n("........ x", "\\.\\.\\.\\.\\.===\\.\\.\\. x", 10);
+}
+
 #[test]
 fn converts_bold_and_italics() {
     transform_expect_1(
@@ -72,24 +176,72 @@ fn converts_bold_and_italics() {
     );
 }
 
+#[test]
+fn nests_bold_inside_italic_for_triple_markers() {
+    transform_expect_1("***both***", "_*both*_");
+    transform_expect_1("___both___", "_*both*_");
+    transform_expect_1("***a** b*", "_*a* b_");
+    transform_expect_1("***a* b**", "*_a_ b*");
+}
+
+#[test]
+fn keeps_nested_bold_italic_balanced_across_splits() {
+    transform_expect_1("***x*** and **_y_**", "_*x*_ and *_y_*");
+    transform_expect_n(
+        "***bold italic words*** then **_more nested text_**",
+        "_*bold italic*_===_*words*_ then===*_more nested_*===*_text_*",
+        16,
+    );
+    // The nested entities open only in the chunk that has their text, not
+    // as an empty `*__*` pair ending the previous one.
+    transform_expect_n(
+        "***bold italic words*** then **_more nested text_**",
+        "_*bold italic*_===_*words*_ then===*_more nested text_*",
+        20,
+    );
+}
+
 #[test]
 fn converts_heading_to_star_heading() {
     transform_expect_1("## 1. What", "*⭐ 1\\. What*");
 }
 
 #[test]
-fn escapes_inline_code_markers() {
+fn reopens_heading_marker_when_heading_exceeds_max_len() {
+    transform_expect_n(
+        "## A very long heading title that will not fit\n\nbody",
+        "*⭐ A very long*===*⭐ heading title*===*⭐ that will not*===*⭐ fit*\nbody",
+        20,
+    );
+}
+
+#[test]
+fn balances_headings_of_only_punctuation_or_emoji() {
+    transform_expect_1("## Note:", "*⭐ Note:*");
+    transform_expect_1("## :", "*⭐ :*");
+    transform_expect_1("## 🎉", "*⭐ 🎉*");
+    transform_expect_1("# !!!", "*🌟 \\!\\!\\!*");
+}
+
+#[test]
+fn keeps_markup_characters_in_inline_code_unescaped() {
     transform_expect_1(
         "`messages = [{role: \"user\"|\"assistant\", content: string}, …]`",
-        "`messages \\= \\[\\{role: \"user\"\\|\"assistant\", content: string\\}, …\\]`",
+        "`messages = [{role: \"user\"|\"assistant\", content: string}, …]`",
     );
 }
 
+#[test]
+fn escapes_punctuation_right_after_inline_code() {
+    transform_expect_1("`code`.", "`code`\\.");
+    transform_expect_1("run `a.b`. Then `c`!", "run `a.b`\\. Then `c`\\!");
+}
+
 #[test]
 fn converts_list_after_blank_line() {
     transform_expect_1(
         "Assume:\n\n- `MODEL_CONTEXT_TOKENS` = max",
-        "Assume:\n⦁ `MODEL\\_CONTEXT\\_TOKENS` \\= max",
+        "Assume:\n⦁ `MODEL_CONTEXT_TOKENS` \\= max",
     );
 }
 
@@ -97,7 +249,7 @@ fn converts_list_after_blank_line() {
 fn converts_list_after_blank_line1() {
     transform_expect_1(
         "Assume.\n- `MODEL_CONTEXT_TOKENS` = max",
-        "Assume\\.\n⦁ `MODEL\\_CONTEXT\\_TOKENS` \\= max",
+        "Assume\\.\n⦁ `MODEL_CONTEXT_TOKENS` \\= max",
     );
 }
 
@@ -105,7 +257,7 @@ fn converts_list_after_blank_line1() {
 fn escapes_inside_code_block_language() {
     transform_expect_1(
         "```text\ntoken_count(text)\n```",
-        "```text\ntoken\\_count\\(text\\)\n```",
+        "```text\ntoken_count(text)\n```",
     );
 }
 
@@ -114,6 +266,36 @@ fn preserves_blockquote_blank_line_between_lines() {
     transform_expect_1("> You\n> \n> Hi", ">You\n>\n>Hi");
 }
 
+#[test]
+fn reopens_formatting_of_nested_list_item_in_next_chunk() {
+    transform_expect_n(
+        "- outer item one\n  - inner **bold words here** end\n  - second inner\n- outer two",
+        "⦁ outer item one===  ⦁ inner *bold*===*words here* end===  ⦁ second inner===⦁ outer two",
+        20,
+    );
+}
+
+#[test]
+fn keeps_empty_list_items_on_their_own_line() {
+    transform_expect_1("-\n- item", "⦁\n⦁ item");
+    transform_expect_1("1.\n2. two", "1\\.\n2\\. two");
+}
+
+#[test]
+fn renders_github_alerts_with_a_title_line() {
+    transform_expect_gfm(
+        "> [!NOTE]\n> Useful info.",
+        ">*ℹ\u{fe0f} Note*\n>Useful info\\.",
+    );
+    transform_expect_gfm(
+        "before\n\n> [!WARNING]\n> Careful **now**.\n\nafter",
+        "before\n\n>*⚠\u{fe0f} Warning*\n>Careful *now*\\.\n\nafter",
+    );
+    transform_expect_gfm("> [!TIP]\n> - a\n> - b", ">*💡 Tip*\n>⦁ a\n>⦁ b");
+    // Not an alert unless the marker is alone on its line.
+    transform_expect_gfm("> [!NOTE] inline", ">\\[\\!NOTE\\] inline");
+}
+
 #[test]
 fn converts_list_items_inside_blockquote() {
     transform_expect_1(
@@ -122,6 +304,12 @@ fn converts_list_items_inside_blockquote() {
     );
 }
 
+#[test]
+fn quotes_lazy_continuation_lines() {
+    transform_expect_1("> line1\nline2\n\nafter", ">line1\n>line2\n\nafter");
+    transform_expect_1("> > nested\nlazy **bold**", ">>nested\n>>lazy *bold*");
+}
+
 #[test]
 fn converts_bold_inside_blockquote() {
     transform_expect_1("> **GOAL:** ", ">*GOAL:*");
@@ -174,6 +362,20 @@ fn converts_ordered_list_to_bullets() {
     transform_expect_1("1. First\n2. Second", "1\\. First\n2\\. Second");
 }
 
+#[test]
+fn converts_strikethrough_to_single_tilde() {
+    transform_expect_1("~~struck~~ text", "~struck~ text");
+}
+
+#[test]
+fn escapes_literal_tilde_after_strikethrough() {
+    transform_expect_1("~~struck~~\\~extra", "~struck~\\~extra");
+    transform_expect_1("~~struck~~\\~\\~x", "~struck~\\~\\~x");
+    transform_expect_1("~~a~~ ~b", "~a~ \\~b");
+    // Three tildes close nothing, so this one is not struck at all.
+    transform_expect_1("~~struck~~~extra", "\\~\\~struck\\~\\~\\~extra");
+}
+
 #[test]
 fn preserves_nested_blockquote_levels() {
     transform_expect_1("> > Nested", ">>Nested");
@@ -187,6 +389,32 @@ fn escapes_parentheses_in_link_url() {
     );
 }
 
+#[test]
+fn flattens_formatting_inside_link_label() {
+    transform_expect_1(
+        "[**bold** _and_ ~~struck~~](https://example.com)",
+        "[bold and struck](https://example\\.com)",
+    );
+    transform_expect_1("**see [the **docs**](u)**", "*see [the docs](u)*");
+}
+
+#[test]
+fn renders_links_as_plain_text_when_configured() {
+    let mut converter = Converter::with_config(ConverterConfig {
+        links_as_text: true,
+        ..Default::default()
+    });
+
+    let chunks = converter.go("[see docs](https://example.com/a_b)").unwrap();
+    assert_eq!(chunks, vec!["see docs \\(https://example\\.com/a\\_b\\)"]);
+
+    let chunks = converter.go("<https://example.com>").unwrap();
+    assert_eq!(chunks, vec!["https://example\\.com"]);
+
+    let chunks = converter.go("<me@example.com>").unwrap();
+    assert_eq!(chunks, vec!["me@example\\.com"]);
+}
+
 #[test]
 fn renders_image_as_link() {
     transform_expect_1(
@@ -195,11 +423,33 @@ fn renders_image_as_link() {
     );
 }
 
+#[test]
+fn drops_formatting_inside_image_alt_text() {
+    transform_expect_1(
+        "![**bold** _alt_](img.png) after",
+        "[Image](img\\.png) after",
+    );
+}
+
+#[test]
+fn image_inside_link_label_becomes_label_text() {
+    transform_expect_1(
+        "[![*badge* alt](badge.svg)](https://ci.example)",
+        "[Image](https://ci\\.example)",
+    );
+    transform_expect_1("[see ![x](x.png) here](u)", "[see Image here](u)");
+}
+
 #[test]
 fn heading_followed_by_list_without_blank_line() {
     transform_expect_1("## Heading\n- item", "*⭐ Heading*\n⦁ item");
 }
 
+#[test]
+fn renders_consecutive_headings_on_separate_lines() {
+    transform_expect_1("# A\n## B\n### C", "*🌟 A*\n*⭐ B*\n*✨ C*");
+}
+
 #[test]
 fn converts_thematic_break_to_em_dash_bar() {
     transform_expect_1(
@@ -222,6 +472,51 @@ fn preserves_newlines_around_list() {
     transform_expect_1(input, expected);
 }
 
+#[test]
+fn starts_blockquote_cleanly_after_list() {
+    transform_expect_1("- item\n\n> quote", "⦁ item\n\n>quote");
+    transform_expect_1(
+        "- item\n  - nested\n\n> quote",
+        "⦁ item\n  ⦁ nested\n\n>quote",
+    );
+}
+
+#[test]
+fn converts_bold_list_item_in_nested_blockquote() {
+    transform_expect_1("> > - **deep**", ">>⦁ *deep*");
+}
+
+#[test]
+fn splits_bold_list_item_in_nested_blockquote() {
+    transform_expect_n(
+        "> > - **deep bold text here** and more words",
+        ">>⦁ *deep bold*===>>*text here* and===>>more words",
+        20,
+    );
+}
+
+#[test]
+fn reemits_quote_markers_in_continuation_chunks() {
+    transform_expect_n(
+        "> one two three four five six seven eight\n>\n> second paragraph words\n\nafter",
+        ">one two three four five===>six seven eight===>second paragraph words===after",
+        24,
+    );
+}
+
+#[test]
+fn renders_blockquote_that_starts_the_document() {
+    transform_expect_1(
+        "\n\n> quote at the very start\n\nafter",
+        ">quote at the very start\n\nafter",
+    );
+    transform_expect_n(
+        "> quote at the very start of it all\n\nafter",
+        ">quote at the very===>start of it all===after",
+        20,
+    );
+}
+
 #[test]
 fn converts_blockquote_with_list_and_bold() {
     let input = "> - Any explicit\n>\n> **text**\n> - greetings";
@@ -287,6 +582,19 @@ fn keeps_code_block_line_len_29() {
     );
 }
 
+#[test]
+fn keeps_blank_lines_inside_code_block() {
+    transform_expect_1(
+        "```\ncode\n\n\nmore\n```\n\nafter",
+        "```\ncode\n\n\nmore\n```\n\nafter",
+    );
+    transform_expect_n(
+        "```\naaaa\n\nbbbb\n```",
+        "```\naaaa\n\n```===```\nbbbb\n```",
+        16,
+    );
+}
+
 #[test]
 fn splits_mixed_text_and_code_block() {
     transform_expect_n(
@@ -297,147 +605,1710 @@ fn splits_mixed_text_and_code_block() {
 }
 
 #[test]
-fn removes_empty_lines_on_split_3() {
-    transform_expect_n("1234567890\n\n1234567890", "1234567890===1234567890", 10);
+fn counts_astral_characters_as_two_utf16_units() {
+    assert_eq!(utf16_len("a𝕏😀é"), 6);
+    assert_eq!(LengthMetric::Utf16.measure("𝕏"), 2);
+    assert_eq!(LengthMetric::Bytes.measure("𝕏"), 4);
+
+    let utf16 = |max_len| {
+        Converter::with_config(ConverterConfig {
+            max_len,
+            length_metric: LengthMetric::Utf16,
+            ..Default::default()
+        })
+    };
+
+    assert_eq!(utf16(6).go("𝕏𝕏𝕏 😀😀😀").unwrap(), vec!["𝕏𝕏𝕏", "😀😀😀"]);
+    // An odd budget must never divide a surrogate pair.
+    assert_eq!(utf16(5).go("𝕏𝕏𝕏𝕏𝕏").unwrap(), vec!["𝕏𝕏", "𝕏𝕏", "𝕏"]);
+    // Byte budgets split astral characters on char boundaries too.
+    transform_expect_n("𝕏𝕏𝕏𝕏𝕏", "𝕏𝕏===𝕏𝕏===𝕏", 10);
 }
 
 #[test]
-fn url_not_split_across_chunks() {
-    let input = "1234567890123456789012345678901234567890123456789012345678901234567890 [see docs](https://example.com/path)";
-    let expected = "1234567890123456789012345678901234567890123456789012345678901234567890===[see docs](https://example\\.com/path)";
-    transform_expect_n(input, expected, 80);
+fn changes_max_len_between_conversions() {
+    let mut converter = Converter::default();
+    assert_eq!(converter.max_len(), 4096);
+    assert_eq!(converter.go("12345 12345").unwrap(), vec!["12345 12345"]);
+
+    converter.set_max_len(16).unwrap();
+    assert_eq!(converter.max_len(), 16);
+    assert_eq!(
+        converter.go("12345678 12345678").unwrap(),
+        vec!["12345678", "12345678"]
+    );
+
+    assert!(converter.set_max_len(10).is_err());
+    assert_eq!(converter.max_len(), 16);
 }
 
 #[test]
-fn asd() {
+fn aligns_wrapped_list_item_text_under_the_bullet_text() {
+    transform_expect_1("- line one\n  line two\n- b", "⦁ line one\n  line two\n⦁ b");
     transform_expect_1(
-        "```rust\n1234567890\n```\n```java\n1234567890\n```",
-        "```rust\n1234567890\n```\n```java\n1234567890\n```",
+        "1. line one\n   line two\n   - nested one\n     nested two\n\n   after",
+        "1\\. line one\n   line two\n  ⦁ nested one\n    nested two\n\n   after",
+    );
+    // A continuation that starts a new chunk is not indented.
+    transform_expect_n(
+        "- a long item\n  with many words in it\n- next",
+        "⦁ a long item\n  with many===words in it\n⦁ next",
+        30,
     );
 }
 
 #[test]
-fn asd1() {
-    transform_expect_1(
-        "You need\n\n1. **Model**\n- `MODEL` test.",
-        "You need\n1\\. *Model*\n  ⦁ `MODEL` test\\.",
-    );
+fn removes_empty_lines_on_split_3() {
+    transform_expect_n("1234567890\n\n1234567890", "1234567890===1234567890", 10);
 }
 
 #[test]
-fn test1() -> anyhow::Result<()> {
-    let input = include_str!("1-input.md");
-    let chunks = Converter::default().go(input)?;
-    let actual = chunks.join("===");
-    let _expected = include_str!("1-output.txt");
+fn reports_split_points_in_source() {
+    let input = "first line\n\nsecond (line) here";
+    let mut converter = Converter::new(20);
+    let chunks = converter.go(input).unwrap();
 
-    std::fs::write("tests/1-output.txt", &actual).unwrap();
-    //assert_eq!(actual, expected);
+    assert_eq!(chunks, vec!["first line\n\nsecond", "\\(line\\) here"]);
+    assert_eq!(converter.split_points(), &[19]);
+    assert_eq!(&input[19..], "(line) here");
 
-    Ok(())
+    converter.go("1234567890\n\n1234567890").unwrap();
+    assert_eq!(converter.split_points(), &[12]);
 }
 
 #[test]
-fn test2() -> anyhow::Result<()> {
-    let input = include_str!("2-input.md");
-    let chunks = Converter::default().go(input)?;
-    let actual = chunks.join("===");
-    let _expected = include_str!("2-output.txt");
-
-    std::fs::write("tests/2-output.txt", &actual).unwrap();
-    //assert_eq!(actual, expected);
-
-    Ok(())
+fn moves_rule_to_next_chunk_without_blank_edges() {
+    transform_expect_n(
+        "a long line of text\n\n---\n\nmore text",
+        "a long line of text===————————\n\nmore text",
+        40,
+    );
 }
 
 #[test]
-fn test3() -> anyhow::Result<()> {
-    let input = include_str!("3-input.md");
-    let chunks = Converter::default().go(input)?;
-    let actual = chunks.join("===");
-    let _expected = include_str!("3-output.txt");
-
-    std::fs::write("tests/3-output.txt", &actual).unwrap();
-    // assert_eq!(actual, expected);
-
-    Ok(())
+fn applies_overflow_strategy_to_oversized_url() {
+    let input = format!("see https://example.com/{} end", "a".repeat(30));
+    let convert = |overflow| {
+        Converter::with_config(ConverterConfig {
+            max_len: 20,
+            overflow,
+            ..Default::default()
+        })
+        .go(&input)
+    };
+
+    assert_eq!(
+        convert(OverflowStrategy::HardSplit).unwrap(),
+        vec![
+            "see",
+            "https://example\\.com",
+            "/aaaaaaaaaaaaaaaaaaa",
+            "aaaaaaaaaaa end"
+        ]
+    );
+    assert_eq!(
+        convert(OverflowStrategy::Truncate).unwrap(),
+        vec!["see", "https://example\\.…", "end"]
+    );
+    assert_eq!(
+        convert(OverflowStrategy::Error)
+            .unwrap_err()
+            .downcast::<Error>()
+            .unwrap(),
+        Error::ChunkTooSmall {
+            needed: 51,
+            max_len: 20
+        }
+    );
 }
 
 #[test]
-fn test4() -> anyhow::Result<()> {
-    let input = include_str!("4-input.md");
-    let chunks = Converter::default().go(input)?;
-    let actual = chunks.join("===");
-    let _expected = include_str!("4-output.txt");
-
-    std::fs::write("tests/4-output.txt", &actual).unwrap();
-    //assert_eq!(actual, expected);
-
-    Ok(())
+fn degrades_oversized_link_to_plain_text() {
+    let input = format!("[docs](https://example.com/{})", "a".repeat(30));
+    transform_expect_n(
+        &input,
+        "docs===\\(https://example\\.c===om/aaaaaaaaaaaaaaaaa===aaaaaaaaaaaaa\\)",
+        20,
+    );
 }
 
 #[test]
-fn test5() -> anyhow::Result<()> {
-    let input = include_str!("5-input.md");
-    let chunks = Converter::default().go(input)?;
-    let actual = chunks.join("===");
-    let _expected = include_str!("5-output.txt");
-
-    std::fs::write("tests/5-output.txt", &actual).unwrap();
-    //assert_eq!(actual, expected);
-
-    Ok(())
+fn previews_first_chunk_with_closed_entities() {
+    let mut converter = Converter::default();
+
+    assert_eq!(
+        converter
+            .go_preview("Intro **bold text that goes on and on** tail", 24)
+            .unwrap(),
+        "Intro *bold text…*"
+    );
+    assert_eq!(
+        converter
+            .go_preview("> quoted _italic words here_ and more", 20)
+            .unwrap(),
+        ">quoted _italic…_"
+    );
+    assert_eq!(converter.go_preview("short", 24).unwrap(), "short");
+    assert_eq!(converter.max_len(), 4096);
 }
 
 #[test]
-fn test6() -> anyhow::Result<()> {
-    let input = include_str!("6-input.md");
-    let chunks = Converter::default().go(input)?;
-    let actual = chunks.join("===");
-    let _expected = include_str!("6-output.txt");
-
-    std::fs::write("tests/6-output.txt", &actual).unwrap();
-    //assert_eq!(actual, expected);
-
-    Ok(())
+fn takes_first_chunks_of_full_conversion() {
+    let input =
+        "First **bold words** here.\n\n> quoted _italic text_ line\n\n- one\n- two\n\nLast.";
+    for continuation in [None, Some((" ↩".to_string(), "↪ ".to_string()))] {
+        let mut converter = Converter::with_config(ConverterConfig {
+            max_len: 20,
+            chunk_continuation: continuation,
+            ..Default::default()
+        });
+        let all = converter.go(input).unwrap();
+        assert!(all.len() > 2);
+
+        assert_eq!(converter.go_take(input, 2).unwrap(), all[..2]);
+        assert_eq!(converter.go_take(input, all.len() + 1).unwrap(), all);
+    }
 }
-#[test]
-fn test7() -> anyhow::Result<()> {
-    let input = include_str!("7-input.md");
-    let chunks = Converter::default().go(input)?;
-    let actual = chunks.join("===");
-    let _expected = include_str!("7-output.txt");
 
-    std::fs::write("tests/7-output.txt", &actual).unwrap();
-    //assert_eq!(actual, expected);
+#[test]
+#[cfg(feature = "debug-stack")]
+fn shows_stack_where_conversion_stopped() {
+    let input = "> quote **bold _nested words here_ more** end";
+    let mut converter = Converter::new(22);
+
+    assert_eq!(
+        converter.go_take(input, 1).unwrap(),
+        vec![">quote *bold _nested_*"]
+    );
+    assert_eq!(
+        converter.debug_stack(),
+        vec!["Strong", "Emphasis", "Prefix(\">\")"]
+    );
 
-    Ok(())
+    converter.go(input).unwrap();
+    assert!(converter.debug_stack().is_empty());
 }
 
 #[test]
-fn test8() -> anyhow::Result<()> {
-    let input = include_str!("8-input.md");
-    let chunks = Converter::default().go(input)?;
-    let actual = chunks.join("===");
-    let _expected = include_str!("8-output.txt");
+fn reports_entities_open_across_chunk_boundaries() {
+    let chunks = Converter::new(20)
+        .go_with_debug("intro **bold words that keep going _and italic too_ on** end")
+        .unwrap();
+    let bounds: Vec<_> = chunks
+        .iter()
+        .map(|chunk| {
+            (
+                chunk.text.as_str(),
+                chunk.open_at_start.clone(),
+                chunk.open_at_end.clone(),
+            )
+        })
+        .collect();
+    let strong = || vec!["Strong".to_string()];
+
+    assert_eq!(
+        bounds,
+        vec![
+            ("intro *bold words*", vec![], strong()),
+            ("*that keep going*", strong(), strong()),
+            ("*_and italic too_*", strong(), strong()),
+            ("*on* end", strong(), vec![]),
+        ]
+    );
+}
 
-    std::fs::write("tests/8-output.txt", &actual).unwrap();
-    //assert_eq!(actual, expected);
+#[test]
+fn counts_entities_in_output() {
+    let mut converter = Converter::default();
+    assert_eq!(
+        converter
+            .entity_count("Some **bold** and **more** words with a [link](https://x.io).")
+            .unwrap(),
+        3
+    );
+    assert_eq!(
+        converter
+            .entity_count("> quoted `code`\n> still quoted\n\ntext\n\n> _again_")
+            .unwrap(),
+        4
+    );
 
-    Ok(())
+    // Every chunk reopens the bold words it continues.
+    let mut converter = Converter::new(20);
+    assert_eq!(
+        converter
+            .entity_count("intro **bold words that keep going** end")
+            .unwrap(),
+        2
+    );
 }
 
 #[test]
-fn test9() -> anyhow::Result<()> {
-    let input = include_str!("9-input.md");
-    let chunks = Converter::default().go(input)?;
-    let actual = chunks.join("===");
-    let _expected = include_str!("9-output.txt");
-
-    std::fs::write("tests/9-output.txt", &actual).unwrap();
-    //assert_eq!(actual, expected);
+fn url_not_split_across_chunks() {
+    let input = "1234567890123456789012345678901234567890123456789012345678901234567890 [see docs](https://example.com/path)";
+    let expected = "1234567890123456789012345678901234567890123456789012345678901234567890===[see docs](https://example\\.com/path)";
+    transform_expect_n(input, expected, 80);
+}
 
-    Ok(())
+#[test]
+fn renders_document_that_is_only_a_code_block() {
+    transform_expect_1("```\ncode\n```\n", "```\ncode\n```");
+    transform_expect_1("text\n\n```rust\ncode\n```\n", "text\n```rust\ncode\n```");
+}
+
+#[test]
+fn batch_converter_shares_config_between_documents() {
+    let mut batch = BatchConverter::new(ConverterConfig {
+        max_len: 16,
+        ..Default::default()
+    });
+
+    let chunks = batch
+        .convert_all(["**bold** text", "12345678 12345678", "# Title"])
+        .unwrap();
+
+    assert_eq!(
+        chunks,
+        vec![
+            vec!["*bold* text".to_string()],
+            vec!["12345678".to_string(), "12345678".to_string()],
+            vec!["*🌟 Title*".to_string()],
+        ]
+    );
+    assert_eq!(batch.config().max_len, 16);
+}
+
+#[test]
+fn asd() {
+    transform_expect_1(
+        "```rust\n1234567890\n```\n```java\n1234567890\n```",
+        "```rust\n1234567890\n```\n```java\n1234567890\n```",
+    );
+}
+
+#[test]
+fn keeps_adjacent_code_blocks_in_different_languages_apart() {
+    let input = "```rust\nlet a = 1;\n```\n```python\nb = 2\n```\nafter";
+    transform_expect_1(
+        input,
+        "```rust\nlet a = 1;\n```\n```python\nb = 2\n```\n\nafter",
+    );
+    transform_expect_n(
+        input,
+        "```rust\nlet a = 1;\n```===```python\nb = 2\n```\n\nafter",
+        35,
+    );
+}
+
+#[test]
+fn keeps_trailing_spaces_of_code_lines_across_splits() {
+    transform_expect_n(
+        "```\nab   \ncd  \nef    \ngh\n```",
+        "```\nab   \ncd  \n```===```\nef    \ngh\n```",
+        24,
+    );
+}
+
+#[test]
+fn puts_block_that_starts_list_item_below_its_marker() {
+    transform_expect_1(
+        "- ```\n  code\n  ```\n- > quoted\n- next",
+        "⦁\n```\ncode\n```\n⦁\n>  quoted\n⦁ next",
+    );
+    transform_expect_n(
+        "intro\n\n1. ```rust\n   let x = 1;\n   ```\n2. two",
+        "intro\n1\\.\n```rust\nlet x = 1;\n```===2\\. two",
+        36,
+    );
+}
+
+#[test]
+fn asd1() {
+    transform_expect_1(
+        "You need\n\n1. **Model**\n- `MODEL` test.",
+        "You need\n1\\. *Model*\n  ⦁ `MODEL` test\\.",
+    );
+}
+
+#[test]
+fn renders_table_as_key_value_rows() {
+    let config = ConverterConfig {
+        enable_tables: true,
+        ..Default::default()
+    };
+    assert_eq!(config.table_mode, TableMode::KeyValue);
+
+    let mut converter = Converter::with_config(config);
+    let chunks = converter
+        .go("| Name | Age |\n|---|---|\n| Ann | 30 |\n| Bob | 4 |")
+        .unwrap();
+
+    assert_eq!(
+        chunks,
+        vec!["*Name*: Ann · *Age*: 30\n*Name*: Bob · *Age*: 4"]
+    );
+
+    // Empty cells leave only their header, and nothing without one.
+    let chunks = converter
+        .go("| a | b | c | |\n|---|---|---|---|\n| 1 | | 3 | |")
+        .unwrap();
+    assert_eq!(chunks, vec!["*a*: 1 · *b* · *c*: 3"]);
+}
+
+#[test]
+fn escapes_literal_pipe_in_table_cells() {
+    let input = "| Op | Meaning |\n|---|---|\n| a \\| b | or |";
+    let convert = |table_mode| {
+        Converter::with_config(ConverterConfig {
+            enable_tables: true,
+            table_mode,
+            ..Default::default()
+        })
+        .go(input)
+        .unwrap()
+    };
+
+    // The literal pipe stays a pipe; columns are separated by a middle dot.
+    assert_eq!(
+        convert(TableMode::KeyValue),
+        vec!["*Op*: a \\| b · *Meaning*: or"]
+    );
+    assert_eq!(
+        convert(TableMode::CodeBlock),
+        vec![
+            "```\n\
+             | Op     | Meaning |\n\
+             |--------|---------|\n\
+             | a \\\\| b | or      |\n\
+             ```"
+        ]
+    );
+}
+
+#[test]
+fn flattens_formatting_inside_table_cells() {
+    let input = "| Name | Note |\n|---|---|\n| Ann | **very** _good_ |";
+    let convert = |table_mode| {
+        Converter::with_config(ConverterConfig {
+            enable_tables: true,
+            table_mode,
+            ..Default::default()
+        })
+        .go(input)
+        .unwrap()
+    };
+
+    assert_eq!(
+        convert(TableMode::KeyValue),
+        vec!["*Name*: Ann · *Note*: very good"]
+    );
+    assert_eq!(
+        convert(TableMode::CodeBlock),
+        vec![
+            "```\n\
+             | Name | Note      |\n\
+             |------|-----------|\n\
+             | Ann  | very good |\n\
+             ```"
+        ]
+    );
+}
+
+#[test]
+fn splits_rendered_markdown_v2_with_bold_across_boundary() {
+    let chunks = Converter::new(20)
+        .split_rendered("Intro *bold text that spans a boundary* and \\_more\\_ text\\.")
+        .unwrap();
+
+    assert_eq!(
+        chunks,
+        vec![
+            "Intro *bold text*",
+            "*that spans a*",
+            "*boundary* and",
+            "\\_more\\_ text\\.",
+        ]
+    );
+}
+
+#[test]
+fn splits_rendered_markdown_v2_code_blocks_and_links() {
+    let chunks = Converter::new(30)
+        .split_rendered(
+            "See ||spoiler __under__|| and `co de`\n\n```py\nline one\nline two\n```\nafter [link](http://x\\.y/\\))",
+        )
+        .unwrap();
+
+    assert_eq!(
+        chunks,
+        vec![
+            "See ||spoiler __under__|| and",
+            "`co de`\n\n```py\nline one\n```",
+            "```py\nline two\n```\nafter",
+            "[link](http://x\\.y/\\))",
+        ]
+    );
+    assert!(Converter::default().split_rendered("*open").is_err());
+    assert!(Converter::default().split_rendered("*a _b* c_").is_err());
+}
+
+#[test]
+fn splits_rendered_quotes_keeping_their_markers() {
+    let mut converter = Converter::new(30);
+    assert_eq!(
+        converter
+            .split_rendered(">quoted line one\n>quoted line two that is long and longer")
+            .unwrap(),
+        vec![
+            ">quoted line one\n>quoted line",
+            ">two that is long and longer"
+        ]
+    );
+    assert_eq!(
+        converter
+            .split_rendered("**>quoted line one\n>quoted line two that is long and longer||\nafter")
+            .unwrap(),
+        vec![
+            "**>quoted line one\n>quoted||",
+            "**>line two that is long and||",
+            "**>longer||\nafter"
+        ]
+    );
+    let input = "text\n>a\n>\n>>b *c*\n**>d ||e||||\nf";
+    assert_eq!(
+        Converter::default().split_rendered(input).unwrap(),
+        vec![input]
+    );
+}
+
+#[test]
+fn keeps_hard_breaks_inside_paragraph() {
+    transform_expect_1(
+        "first line  \nsecond line\\\nthird",
+        "first line\nsecond line\nthird",
+    );
+}
+
+#[test]
+fn breaks_lines_at_br_tags() {
+    transform_expect_1("line1<br>line2", "line1\nline2");
+    transform_expect_1("a<br/>b<BR />c <b>d</b>", "a\nb\nc <b\\>d</b\\>");
+}
+
+#[test]
+fn drops_html_comments() {
+    transform_expect_1("keep<!-- hidden --> this", "keep this");
+    transform_expect_1("a <!-- hidden --> b", "a b");
+    transform_expect_1("a <!-- one --> <!-- two --> b", "a b");
+    transform_expect_1(
+        "intro\n\n<!-- spans\nseveral\n\nlines -->\n\nafter `<!-- code -->`",
+        "intro\n\nafter `<!-- code -->`",
+    );
+}
+
+#[test]
+fn handles_message_that_is_only_an_oversized_url() {
+    let url = format!("https://example.com/{}", "a".repeat(5000));
+    let convert = |overflow| {
+        Converter::with_config(ConverterConfig {
+            overflow,
+            ..Default::default()
+        })
+        .go(&url)
+    };
+
+    let chunks = convert(OverflowStrategy::HardSplit).unwrap();
+    assert_eq!(chunks.len(), 2);
+    assert_eq!(chunks.concat(), url.replace('.', "\\."));
+    assert_eq!(
+        convert(OverflowStrategy::Error)
+            .unwrap_err()
+            .downcast::<Error>()
+            .unwrap(),
+        Error::ChunkTooSmall {
+            needed: 5021,
+            max_len: 4096
+        }
+    );
+}
+
+#[test]
+fn fails_when_heading_prefix_leaves_no_room_for_text() {
+    let too_small = |needed, max_len| Error::ChunkTooSmall { needed, max_len };
+    for max_len in 1..=6 {
+        assert_eq!(
+            Converter::new(max_len)
+                .go("# Title")
+                .unwrap_err()
+                .downcast::<Error>()
+                .unwrap(),
+            too_small(8, max_len)
+        );
+    }
+    let hashes = |max_len| {
+        Converter::with_config(ConverterConfig {
+            max_len,
+            heading_style: HeadingStyle::Hashes,
+            ..Default::default()
+        })
+        .go("###### Title")
+    };
+    for max_len in [9, 10, 15] {
+        assert_eq!(
+            hashes(max_len).unwrap_err().downcast::<Error>().unwrap(),
+            too_small(16, max_len)
+        );
+    }
+    assert_eq!(
+        hashes(16).unwrap(),
+        vec![
+            "_\\#\\#\\#\\#\\#\\# T_",
+            "_\\#\\#\\#\\#\\#\\# i_",
+            "_\\#\\#\\#\\#\\#\\# t_",
+            "_\\#\\#\\#\\#\\#\\# l_",
+            "_\\#\\#\\#\\#\\#\\# e_"
+        ]
+    );
+}
+
+#[test]
+fn fails_when_repeated_markup_leaves_no_room_for_text() {
+    let input = "> > > > > > > > > **bold words**";
+    let convert = |max_len| Converter::new(max_len).go(input);
+
+    // Nine quote markers plus the bold markers take up the whole chunk.
+    assert_eq!(
+        convert(10).unwrap_err().downcast::<Error>().unwrap(),
+        Error::ChunkTooSmall {
+            needed: 12,
+            max_len: 10
+        }
+    );
+    assert_eq!(
+        convert(13).unwrap(),
+        vec![
+            ">>>>>>>>>*bo*",
+            ">>>>>>>>>*ld*",
+            ">>>>>>>>>*wo*",
+            ">>>>>>>>>*rd*",
+            ">>>>>>>>>*s*"
+        ]
+    );
+}
+
+#[test]
+fn renders_code_block_inside_list_item() {
+    transform_expect_1(
+        "1. item\n   ```rust\n   let x = 1;\n   ```\n2. next",
+        "1\\. item\n```rust\nlet x = 1;\n```\n2\\. next",
+    );
+    transform_expect_1(
+        "- item\n\n  ```\n  code\n  ```\n\n  after code\n- next",
+        "⦁ item\n```\ncode\n```\n\n  after code\n⦁ next",
+    );
+}
+
+#[test]
+fn marks_chunk_boundaries_with_continuation_indicators() {
+    let mut converter = Converter::with_config(ConverterConfig {
+        max_len: 20,
+        chunk_continuation: Some((" ↩".to_string(), "↪ ".to_string())),
+        ..Default::default()
+    });
+
+    let chunks = converter.go("one two three four five six seven").unwrap();
+    assert_eq!(
+        chunks,
+        vec!["one two ↩", "↪ three four ↩", "↪ five six ↩", "↪ seven"]
+    );
+    assert!(chunks.iter().all(|chunk| chunk.len() <= 20));
+
+    let chunks = converter.go("> quoted words that wrap around").unwrap();
+    assert_eq!(
+        chunks,
+        vec![">quoted ↩", ">↪ words that ↩", ">↪ wrap around"]
+    );
+
+    assert_eq!(converter.go("short").unwrap(), vec!["short"]);
+}
+
+#[test]
+fn keeps_expandable_quote_markers_around_continuation_indicators() {
+    let mut converter = Converter::with_config(ConverterConfig {
+        max_len: 40,
+        chunk_continuation: Some((" ↩".to_string(), "↪ ".to_string())),
+        ..Default::default()
+    });
+
+    let chunks = converter
+        .go_validated(
+            "<details><summary>S</summary>\n\n\
+             body text here that is long enough to split\n</details>\n\n\
+             after paragraph",
+        )
+        .unwrap();
+    assert_eq!(
+        chunks,
+        vec![
+            "**>*S*\n>body text here that is ↩||",
+            "**>↪ long enough to split||\n\nafter ↩",
+            "↪ paragraph"
+        ]
+    );
+}
+
+#[test]
+fn renders_angle_bracket_autolinks_as_links() {
+    transform_expect_1(
+        "see <https://example.com/a_b> or <me@example.com>",
+        "see [https://example\\.com/a\\_b](https://example\\.com/a\\_b) \
+         or [me@example\\.com](mailto:me@example\\.com)",
+    );
+}
+
+#[test]
+fn labels_blank_links_with_their_url() {
+    transform_expect_1(
+        "see [   ](https://x.io) and [ `  ` ](https://y.io)",
+        "see [https://x\\.io](https://x\\.io) and [https://y\\.io](https://y\\.io)",
+    );
+}
+
+#[test]
+fn rewrites_link_urls_before_escaping() {
+    let mut converter = Converter::with_config(ConverterConfig {
+        autolink_literals: true,
+        url_rewriter: Some(UrlRewriter::new(|url| {
+            let sep = if url.contains('?') { '&' } else { '?' };
+            format!("{url}{sep}utm_source=bot")
+        })),
+        ..Default::default()
+    });
+
+    let chunks = converter
+        .go("[docs](https://x.io/a?b=1) and ![pic](https://x.io/p.png) at www.y.com")
+        .unwrap();
+    assert_eq!(
+        chunks,
+        vec![
+            "[docs](https://x\\.io/a?b\\=1&utm\\_source\\=bot) and \
+             [Image](https://x\\.io/p\\.png?utm\\_source\\=bot) at \
+             [www\\.y\\.com](http://www\\.y\\.com?utm\\_source\\=bot)"
+        ]
+    );
+
+    // A rewriter doesn't keep the converter from crossing `catch_unwind`.
+    let config = ConverterConfig {
+        url_rewriter: Some(UrlRewriter::new(|url| format!("{url}/bot"))),
+        ..Default::default()
+    };
+    let chunks = std::panic::catch_unwind(|| Converter::with_config(config).go("[a](b)"))
+        .unwrap()
+        .unwrap();
+    assert_eq!(chunks, vec!["[a](b/bot)"]);
+}
+
+#[test]
+fn links_bare_urls_when_autolink_literals_enabled() {
+    let mut converter = Converter::with_config(ConverterConfig {
+        autolink_literals: true,
+        ..Default::default()
+    });
+
+    let chunks = converter
+        .go("see www.example.com/a_b. or (https://x.io/p) but not `www.y.com`")
+        .unwrap();
+    assert_eq!(
+        chunks,
+        vec![
+            "see [www\\.example\\.com/a\\_b](http://www\\.example\\.com/a\\_b)\\. \
+             or \\([https://x\\.io/p](https://x\\.io/p)\\) but not `www.y.com`"
+        ]
+    );
+}
+
+#[test]
+fn uses_configured_image_fallback_label() {
+    let convert = |label: &str| {
+        Converter::with_config(ConverterConfig {
+            image_fallback_label: label.to_string(),
+            ..Default::default()
+        })
+        .go("![](img.png) after")
+        .unwrap()
+    };
+
+    assert_eq!(convert("📷"), vec!["[📷](img\\.png) after"]);
+    assert_eq!(convert(""), vec!["img\\.png after"]);
+    assert_eq!(convert("  "), vec!["img\\.png after"]);
+}
+
+#[test]
+fn drops_images_from_captions() {
+    let input = "Sunset ![the *beach*](beach.jpg)\n\n[![logo](logo.png)](https://x.io) in **Bali** \
+                 ![flag](id.png)!";
+    let chunks = Converter::for_caption().go(input).unwrap();
+    assert_eq!(
+        chunks,
+        vec!["Sunset\n\n[https://x\\.io](https://x\\.io) in *Bali*\\!"]
+    );
+    assert_eq!(Converter::for_caption().max_len(), 1024);
+}
+
+#[test]
+fn renders_compact_output_without_decoration() {
+    let input = "# Title\n\nIntro text.\n\n## Section\n\n- one\n- two\n  - nested\n\n\
+                 1. first\n2. second\n\n> quote\n\nEnd.";
+    let default = Converter::default().go(input).unwrap();
+    assert_eq!(
+        default,
+        vec![
+            "*🌟 Title*\nIntro text\\.\n\n*⭐ Section*\n⦁ one\n⦁ two\n  ⦁ nested\n\
+             1\\. first\n2\\. second\n\n>quote\n\nEnd\\."
+        ]
+    );
+    let compact = Converter::compact().go(input).unwrap();
+    assert_eq!(
+        compact,
+        vec![
+            "*Title*\nIntro text\\.\n*Section*\n• one\n• two\n  • nested\n\
+             1\\. first\n2\\. second\n>quote\nEnd\\."
+        ]
+    );
+}
+
+#[test]
+fn does_not_split_at_non_breaking_space() {
+    transform_expect_n(
+        "some text 10\u{a0}km away",
+        "some text===10\u{a0}km away",
+        15,
+    );
+}
+
+#[test]
+fn never_returns_empty_chunks() {
+    for (input, max_len) in [
+        ("1234567890", 10),
+        ("12345 1234\n\n", 10),
+        ("1234567890\n\n---", 10),
+        ("> 123456789\n>\n> 12345", 10),
+        ("1234567890\n\n```\n```", 10),
+    ] {
+        let chunks = Converter::new(max_len).go(input).unwrap();
+        assert!(
+            chunks.iter().all(|chunk| !chunk.trim().is_empty()),
+            "{input:?} produced {chunks:?}"
+        );
+    }
+}
+
+#[test]
+fn converts_with_defaults_in_one_call() {
+    let input = format!("**Hi**\n\n{}", "word ".repeat(1000));
+    let converted = chunks(&input).unwrap();
+    assert_eq!(converted.len(), 2);
+    assert_eq!(converted, Converter::default().go(&input).unwrap());
+    assert_eq!(chunks("").unwrap(), Vec::<String>::new());
+}
+
+#[test]
+fn converts_documents_independently() {
+    let chunks = Converter::default()
+        .go_multi(&["> quoted **bold", "- item\n\nplain *text*"])
+        .unwrap();
+
+    assert_eq!(
+        chunks,
+        vec![vec![">quoted \\*\\*bold"], vec!["⦁ item\n\nplain _text_"]]
+    );
+}
+
+#[test]
+fn appends_messages_to_earlier_chunks() {
+    let mut converter = Converter::new(30);
+    assert_eq!(
+        converter.go_append("Hi **there**").unwrap(),
+        vec!["Hi *there*"]
+    );
+    assert_eq!(
+        converter.go_append("> quoted reply").unwrap(),
+        vec!["Hi *there*\n\n>quoted reply"]
+    );
+    assert_eq!(
+        converter
+            .go_append("- a\n- b\n\nthen a longer message")
+            .unwrap(),
+        vec![
+            "Hi *there*\n\n>quoted reply",
+            "⦁ a\n⦁ b\n\nthen a longer",
+            "message"
+        ]
+    );
+
+    // Any other conversion starts over.
+    converter.go("reset").unwrap();
+    assert_eq!(converter.go_append("fresh").unwrap(), vec!["fresh"]);
+}
+
+#[test]
+fn splits_sections_on_thematic_breaks() {
+    let chunks = Converter::default()
+        .go_sections("# One\ntext\n\n---\n\nTwo\n---\nmore\n\n***\n\nThree")
+        .unwrap();
+
+    assert_eq!(
+        chunks,
+        vec![
+            vec!["*🌟 One*\ntext"],
+            vec!["*⭐ Two*\nmore"],
+            vec!["Three"]
+        ]
+    );
+}
+
+#[test]
+fn renders_every_thematic_break_spelling_as_rule() {
+    for rule in ["---", "***", "___", "* * *", "- - -", "_ _ _"] {
+        transform_expect_1(&format!("a\n\n{rule}\n\nb"), "a\n\n————————\n\nb");
+    }
+
+    let chunks = Converter::with_config(ConverterConfig {
+        thematic_break: "* * *".to_string(),
+        ..Default::default()
+    })
+    .go("a\n\n***\n\nb")
+    .unwrap();
+    assert_eq!(chunks, vec!["a\n\n\\* \\* \\*\n\nb"]);
+}
+
+#[test]
+fn fills_chunk_right_after_heading() {
+    let convert = |length_metric, max_len| {
+        Converter::with_config(ConverterConfig {
+            max_len,
+            length_metric,
+            ..Default::default()
+        })
+        .go("# Title words\nbody text here")
+        .unwrap()
+    };
+
+    // `*🌟 Title words*\nbody` is 21 UTF-16 code units and 23 bytes.
+    assert_eq!(
+        convert(LengthMetric::Utf16, 21),
+        vec!["*🌟 Title words*\nbody", "text here"]
+    );
+    assert_eq!(
+        convert(LengthMetric::Utf16, 20),
+        vec!["*🌟 Title words*", "body text here"]
+    );
+    assert_eq!(
+        convert(LengthMetric::Bytes, 23),
+        vec!["*🌟 Title words*\nbody", "text here"]
+    );
+}
+
+#[test]
+fn renders_headings_with_hash_prefixes() {
+    let chunks = Converter::with_config(ConverterConfig {
+        heading_style: HeadingStyle::Hashes,
+        ..Default::default()
+    })
+    .go("### Setup\ntext\n\n###### Tiny")
+    .unwrap();
+
+    assert_eq!(
+        chunks,
+        vec!["*\\#\\#\\# Setup*\ntext\n\n_\\#\\#\\#\\#\\#\\# Tiny_"]
+    );
+
+    // The prefix is never split, so a chunk too small for it is an error.
+    let small = Converter::with_config(ConverterConfig {
+        max_len: 12,
+        heading_style: HeadingStyle::Hashes,
+        ..Default::default()
+    })
+    .go("###### Title");
+    assert!(small.unwrap_err().downcast::<Error>().is_ok());
+}
+
+#[test]
+fn underlines_headings_when_configured() {
+    let convert = |max_len| {
+        Converter::with_config(ConverterConfig {
+            max_len,
+            heading_style: HeadingStyle::Underlined,
+            ..Default::default()
+        })
+        .go("intro\n\n## Set *up*!\ntext\n\n> ###### Tiny")
+        .unwrap()
+    };
+
+    assert_eq!(
+        convert(4096),
+        vec!["intro\n\n*Set _up_\\!*\n───────\ntext\n\n>_Tiny_\n>────"]
+    );
+    // An underline that doesn't fit moves to the next chunk, cut to its size.
+    assert_eq!(
+        convert(20),
+        vec![
+            "intro\n\n*Set _up_\\!*",
+            "──────",
+            "text\n\n>_Tiny_",
+            ">────"
+        ]
+    );
+}
+
+#[test]
+fn renders_headings_past_max_level_as_bold() {
+    let convert = |max_len| {
+        Converter::with_config(ConverterConfig {
+            max_len,
+            max_heading_level: 4,
+            ..Default::default()
+        })
+        .go("#### Four\n\n##### Five *it*\n\ntext\n\n###### Six long heading words")
+        .unwrap()
+    };
+
+    assert_eq!(
+        convert(4096),
+        vec!["*🔸 Four*\n*Five _it_*\ntext\n\n*Six long heading words*"]
+    );
+    assert_eq!(
+        convert(14),
+        vec![
+            "*🔸 Four*",
+            "*Five _it_*",
+            "text\n\n*Six*",
+            "*long heading*",
+            "*words*"
+        ]
+    );
+}
+
+#[test]
+fn drops_heading_attributes_when_enabled() {
+    let input = "## Title {#anchor}\n\n## Set {a, b}";
+    transform_expect_1(input, "*⭐ Title \\{\\#anchor\\}*\n*⭐ Set \\{a, b\\}*");
+
+    let chunks = Converter::with_config(ConverterConfig {
+        heading_attributes: true,
+        ..Default::default()
+    })
+    .go(input)
+    .unwrap();
+    assert_eq!(chunks, vec!["*⭐ Title*\n*⭐ Set*"]);
+}
+
+#[test]
+fn splits_on_entity_count_before_length() {
+    let mut converter = Converter::with_config(ConverterConfig {
+        max_entities_per_chunk: Some(3),
+        ..Default::default()
+    });
+
+    let chunks = converter
+        .go("**a** **b** _c_ **d** `e` **f** g\n\n> quoted **h** and [x](y) ~~z~~")
+        .unwrap();
+    assert_eq!(
+        chunks,
+        vec![
+            "*a* *b* _c_",
+            "*d* `e` *f* g",
+            ">quoted *h* and [x](y)",
+            ">~z~"
+        ]
+    );
+}
+
+#[test]
+fn numbers_nested_ordered_lists_legally() {
+    let input = "1. a\n   1. b\n   2. c\n2. d\n   1. e\n      - f\n        1. g";
+    transform_expect_1(
+        input,
+        "1\\. a\n  1\\. b\n  2\\. c\n2\\. d\n  1\\. e\n    ⦁ f\n      1\\. g",
+    );
+
+    let chunks = Converter::with_config(ConverterConfig {
+        legal_numbering: true,
+        ..Default::default()
+    })
+    .go(input)
+    .unwrap();
+    assert_eq!(
+        chunks,
+        vec!["1\\. a\n  1\\.1 b\n  1\\.2 c\n2\\. d\n  2\\.1 e\n    ⦁ f\n      1\\. g"]
+    );
+}
+
+#[test]
+fn numbers_lists_starting_near_the_largest_marker() {
+    transform_expect_1(
+        "999999998. a\n999999999. b\n999999999. c",
+        "999999998\\. a\n999999999\\. b\n1000000000\\. c",
+    );
+}
+
+#[test]
+fn validates_rendered_chunks() {
+    let input = "# Title\n\n**bold _both_** and `code` with [a link](https://x.io).\n\n\
+                 > quote ||not a spoiler||\n\n<details>\n<summary>More</summary>\n\nhidden\n\n</details>";
+    let mut converter = Converter::new(40);
+    assert_eq!(
+        converter.go_validated(input).unwrap(),
+        converter.go(input).unwrap()
+    );
+
+    // Continuation markers are inserted verbatim, so a bare `.` slips through.
+    let mut converter = Converter::with_config(ConverterConfig {
+        max_len: 20,
+        chunk_continuation: Some((" ...".to_string(), String::new())),
+        ..Default::default()
+    });
+    let input = "one two three four five six seven";
+    assert!(converter.go(input).is_ok());
+    let err = converter.go_validated(input).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "chunk 0 would be rejected by Telegram: unescaped '.' at byte 14"
+    );
+}
+
+#[test]
+fn trims_chunk_boundaries_per_mode() {
+    let convert = |chunk_trim, input| {
+        Converter::with_config(ConverterConfig {
+            max_len: 18,
+            chunk_trim,
+            ..Default::default()
+        })
+        .go(input)
+        .unwrap()
+    };
+    let list = "- first item words here\n  - nested item words";
+
+    assert_eq!(
+        convert(ChunkTrim::None, list),
+        vec!["⦁ first item ", "words here", "  ⦁ nested item ", "words"]
+    );
+    assert_eq!(
+        convert(ChunkTrim::Trailing, list),
+        vec!["⦁ first item", "words here", "  ⦁ nested item", "words"]
+    );
+    assert_eq!(
+        convert(ChunkTrim::Both, list),
+        vec!["⦁ first item", "words here", "⦁ nested item", "words"]
+    );
+
+    let code = "```\naaaa    bbbb    cccc\n```";
+    for chunk_trim in [ChunkTrim::None, ChunkTrim::Trailing, ChunkTrim::Both] {
+        assert_eq!(
+            convert(chunk_trim, code),
+            vec!["```\naaaa    ```", "```\nbbbb    ```", "```\ncccc\n```"]
+        );
+    }
+}
+
+#[test]
+fn writes_line_breaks_with_configured_newline() {
+    let convert = |max_len, input| {
+        Converter::with_config(ConverterConfig {
+            max_len,
+            newline: "\r\n".to_string(),
+            ..Default::default()
+        })
+        .go(input)
+        .unwrap()
+    };
+
+    assert_eq!(
+        convert(4096, "one\ntwo\n\n```rs\nlet x;\n```"),
+        vec!["one\r\ntwo\r\n```rs\r\nlet x;\r\n```"]
+    );
+    // The longer line break counts towards max_len.
+    assert_eq!(convert(9, "aaaa\nbbbb"), vec!["aaaa", "bbbb"]);
+    assert_eq!(convert(10, "aaaa\nbbbb"), vec!["aaaa\r\nbbbb"]);
+}
+
+#[test]
+fn marks_quotes_with_configured_prefix() {
+    let convert = |max_len, input| {
+        Converter::with_config(ConverterConfig {
+            max_len,
+            quote_prefix: "▎ ".to_string(),
+            ..Default::default()
+        })
+        .go(input)
+        .unwrap()
+    };
+    let input = "> quoted line here\n>\n> > nested words\n\nafter";
+
+    assert_eq!(
+        convert(4096, input),
+        vec!["▎ quoted line here\n▎\n▎ ▎ nested words\n\nafter"]
+    );
+    // Each marker takes four bytes of the chunk.
+    assert_eq!(
+        convert(20, input),
+        vec!["▎ quoted line here", "▎ ▎ nested words", "after"]
+    );
+
+    // A list item marker that moves to the next chunk is cut off whole,
+    // never through a multi-byte quote marker.
+    let convert = |max_len, quote_prefix: &str| {
+        Converter::with_config(ConverterConfig {
+            max_len,
+            quote_prefix: quote_prefix.to_string(),
+            ..Default::default()
+        })
+        .go("> - item one two\n> - item2 three four")
+        .unwrap()
+    };
+    assert_eq!(
+        convert(16, "»"),
+        vec!["»⦁ item one", "»two", "»⦁ item2", "»three four"]
+    );
+    assert_eq!(
+        convert(11, "▎"),
+        vec!["▎⦁ item", "▎one two", "▎⦁ item", "▎2 three", "▎four"]
+    );
+}
+
+#[test]
+fn starts_chunk_per_top_level_block_when_configured() {
+    let convert = |chunk_strategy, max_len, input| {
+        Converter::with_config(ConverterConfig {
+            max_len,
+            chunk_strategy,
+            ..Default::default()
+        })
+        .go(input)
+        .unwrap()
+    };
+    let input = "# Title\n\nFirst paragraph.\n\n- a\n- b\n\n> quote\n\n```\ncode\n```";
+
+    assert_eq!(
+        convert(ChunkStrategy::Greedy, 4096, input),
+        vec!["*🌟 Title*\nFirst paragraph\\.\n⦁ a\n⦁ b\n\n>quote\n```\ncode\n```"]
+    );
+    assert_eq!(
+        convert(ChunkStrategy::PerBlock, 4096, input),
+        vec![
+            "*🌟 Title*",
+            "First paragraph\\.",
+            "⦁ a\n⦁ b",
+            ">quote",
+            "```\ncode\n```"
+        ]
+    );
+
+    // Blocks that don't fit are still split.
+    let input = "First paragraph here.\n\nsecond paragraph words that are long";
+    assert_eq!(
+        convert(ChunkStrategy::Greedy, 20, input),
+        vec![
+            "First paragraph",
+            "here\\.\n\nsecond",
+            "paragraph words that",
+            "are long"
+        ]
+    );
+    assert_eq!(
+        convert(ChunkStrategy::PerBlock, 20, input),
+        vec![
+            "First paragraph",
+            "here\\.",
+            "second paragraph",
+            "words that are long"
+        ]
+    );
+}
+
+#[test]
+fn spaces_quote_markers_when_configured() {
+    let convert = |quote_space, max_len| {
+        Converter::with_config(ConverterConfig {
+            max_len,
+            quote_space,
+            ..Default::default()
+        })
+        .go("> hello there\n>\n> > nested **bold** words\n\nafter")
+        .unwrap()
+    };
+
+    assert_eq!(
+        convert(false, 4096),
+        vec![">hello there\n>\n>>nested *bold* words\n\nafter"]
+    );
+    assert_eq!(
+        convert(true, 4096),
+        vec!["> hello there\n>\n>> nested *bold* words\n\nafter"]
+    );
+    assert_eq!(
+        convert(false, 16),
+        vec![">hello there", ">>nested *bold*", ">>words\n\nafter"]
+    );
+    assert_eq!(
+        convert(true, 16),
+        vec!["> hello there", ">> nested *bold*", ">> words\n\nafter"]
+    );
+}
+
+#[test]
+fn strips_invisible_control_characters_when_configured() {
+    let input = "file\u{202E}gpj.exe and zero\u{200B}width `a\u{2066}b` 👨\u{200D}👩";
+    transform_expect_1(
+        input,
+        "file\u{202E}gpj\\.exe and zero\u{200B}width `a\u{2066}b` 👨\u{200D}👩",
+    );
+
+    let chunks = Converter::with_config(ConverterConfig {
+        strip_invisible_controls: true,
+        ..Default::default()
+    })
+    .go(input)
+    .unwrap();
+    assert_eq!(
+        chunks,
+        vec!["filegpj\\.exe and zerowidth `ab` 👨\u{200D}👩"]
+    );
+}
+
+#[test]
+fn strips_emoji_when_configured() {
+    let input = "# 🚀 Launch day\n\nHello 👋 world, ✅ done ✔️\nfamily 👨\u{200D}👩 🇩🇪 ok 👍🏽 \
+                 `code 🙂`\n\n> [!TIP]\n> try it\n\n- [x] shipped ⭐\n\n## 🎉\n\n**🎉 bold** end ©";
+    let chunks = Converter::with_config(ConverterConfig {
+        strip_emoji: true,
+        enable_task_lists: true,
+        enable_alerts: true,
+        ..Default::default()
+    })
+    .go(input)
+    .unwrap();
+    assert_eq!(
+        chunks,
+        vec![
+            "*Launch day*\nHello world, done\nfamily ok `code 🙂`\n\n>*Tip*\n>try it\n\
+             ⦁ ☒ shipped\n\n*bold* end ©"
+        ]
+    );
+}
+
+#[test]
+fn renders_legacy_markdown_dialect() {
+    let mut converter = Converter::with_config(ConverterConfig {
+        dialect: Dialect::MarkdownV1,
+        ..Default::default()
+    });
+
+    let chunks = converter
+        .go("**bold _nested_ a_b** and _it_ ~~gone~~ `co_de` snake_case 1.5 [x_y](http://a.b/c_d)")
+        .unwrap();
+    assert_eq!(
+        chunks,
+        vec!["*bold nested a_b* and _it_ gone `co_de` snake\\_case 1.5 [x_y](http://a.b/c_d)"]
+    );
+
+    let chunks = converter
+        .go("# Title\n\n1. one\n2. two\n\n```rust\nlet a = b*c;\n```")
+        .unwrap();
+    assert_eq!(
+        chunks,
+        vec!["*🌟 Title*\n1. one\n2. two\n```rust\nlet a = b*c;\n```"]
+    );
+}
+
+#[test]
+fn prepends_table_of_contents_from_headings() {
+    let input = "# Guide\n\nintro\n\n## Setup `cargo` v1.0\n\nsteps\n\n### Linux\n\nmore";
+    let mut converter = Converter::with_config(ConverterConfig {
+        table_of_contents: true,
+        ..Default::default()
+    });
+
+    assert_eq!(
+        converter.go(input).unwrap(),
+        vec![
+            "⦁ Guide\n  ⦁ Setup cargo v1\\.0\n    ⦁ Linux",
+            "*🌟 Guide*\nintro\n\n*⭐ Setup `cargo` v1\\.0*\nsteps\n\n*✨ Linux*\nmore"
+        ]
+    );
+    assert_eq!(converter.go("no headings").unwrap(), vec!["no headings"]);
+}
+
+#[test]
+fn escapes_code_content_minimally_everywhere() {
+    let input = "`a*_[b` **`c*_[`** [l `d*_[`](http://x)\n\n# h `e*_[`\n\n\
+                 | k |\n|---|\n| f*_[ |\n\n```\ng*_[\n```";
+    let chunks = Converter::with_config(ConverterConfig {
+        escape_mode: EscapeMode::Minimal,
+        enable_tables: true,
+        table_mode: TableMode::CodeBlock,
+        ..Default::default()
+    })
+    .go(input)
+    .unwrap();
+
+    assert_eq!(
+        chunks,
+        vec![
+            "`a*_[b` *`c*_[`* [l `d*_[`](http://x)\n\n*🌟 h `e*_[`*\n\
+             ```\n| k    |\n|------|\n| f*_[ |\n```\n```\ng*_[\n```"
+        ]
+    );
+}
+
+#[test]
+fn escapes_single_strings_per_context() {
+    let unescape = |escaped: &str| {
+        let mut out = String::new();
+        let mut chars = escaped.chars();
+        while let Some(ch) = chars.next() {
+            out.extend(if ch == '\\' { chars.next() } else { Some(ch) });
+        }
+        out
+    };
+    let text = r"a_b (c) `d` \e [f]! 1.5";
+    let cases = [
+        (EntityContext::Text, r"a\_b \(c\) \`d\` \\e \[f\]\! 1\.5"),
+        (
+            EntityContext::LinkLabel,
+            r"a\_b \(c\) \`d\` \\e \[f\]\! 1\.5",
+        ),
+        (EntityContext::Code, r"a_b (c) \`d\` \\e [f]! 1.5"),
+        (EntityContext::Pre, r"a_b (c) \`d\` \\e [f]! 1.5"),
+        (EntityContext::LinkUrl, r"a_b (c\) `d` \\e [f]! 1.5"),
+    ];
+    for (context, expected) in cases {
+        let escaped = escape_for(context, text);
+        assert_eq!(escaped, expected, "{context:?}");
+        assert_eq!(unescape(&escaped), text, "{context:?}");
+    }
+}
+
+#[test]
+fn validates_config_before_converting() {
+    let validate = |config: ConverterConfig| Converter::with_config(config).validate_config();
+
+    assert!(validate(ConverterConfig::default()).is_ok());
+    let err = validate(ConverterConfig {
+        max_len: 5,
+        ..Default::default()
+    })
+    .unwrap_err();
+    assert_eq!(err.to_string(), "max_len 5 is below the minimum of 16");
+
+    let err = validate(ConverterConfig {
+        max_len: 20,
+        chunk_continuation: Some((" ↩".to_string(), "↪ ".to_string())),
+        ..Default::default()
+    })
+    .unwrap_err();
+    assert!(err.to_string().contains("chunk_continuation"));
+
+    let hashes = |max_len| ConverterConfig {
+        max_len,
+        heading_style: HeadingStyle::Hashes,
+        ..Default::default()
+    };
+    // The minimum leaves room for the widest heading prefix.
+    assert!(validate(hashes(12)).is_err());
+    assert!(validate(hashes(MIN_MAX_LEN)).is_ok());
+    assert!(
+        Converter::with_config(hashes(MIN_MAX_LEN))
+            .go_validated("###### Title")
+            .is_ok()
+    );
+
+    let err = validate(ConverterConfig {
+        max_chunks: Some(0),
+        ..Default::default()
+    })
+    .unwrap_err();
+    assert!(err.to_string().contains("max_chunks"));
+}
+
+#[test]
+fn renders_task_markers_in_unusual_places() {
+    transform_expect_gfm("[x] not a task", "\\[x\\] not a task");
+    transform_expect_gfm("> - [x] quoted\n> - [ ] task", ">⦁ ☑️ quoted\n>⦁ ☐ task");
+    transform_expect_gfm("1. [x] **bold** task", "1\\. ☑️ *bold* task");
+    transform_expect_gfm(
+        "- [x] a\n\n  [ ] continuation",
+        "⦁ ☑️ a\n\n  \\[ \\] continuation",
+    );
+}
+
+#[test]
+fn renders_task_list_inside_definition() {
+    transform_expect_gfm(
+        "Term\n: Definition with tasks\n\n  - [ ] todo\n  - [x] done\n\nafter",
+        "*Term*\n\nDefinition with tasks\n⦁ ☐ todo\n⦁ ☑️ done\n\nafter",
+    );
+    transform_expect_gfm(
+        "- item\n\n  Term\n  : def\n- [ ] task",
+        "⦁ item\n\n  *Term*\n  def\n⦁ ☐ task",
+    );
+}
+
+#[test]
+fn leaves_gfm_extensions_as_text_by_default() {
+    let convert = |input| Converter::default().go(input).unwrap();
+
+    assert_eq!(convert("- [x] done"), vec!["⦁ \\[x\\] done"]);
+    assert_eq!(convert("Term\n: def"), vec!["Term\n: def"]);
+    assert_eq!(
+        convert("> [!NOTE]\n> Useful info."),
+        vec![">\\[\\!NOTE\\]\n>Useful info\\."]
+    );
+}
+
+#[test]
+fn reports_room_left_in_last_chunk() {
+    let mut converter = Converter::new(20);
+
+    converter.go("one").unwrap();
+    assert_eq!(converter.remaining_in_last_chunk(), 17);
+
+    converter.go("one two").unwrap();
+    assert_eq!(converter.remaining_in_last_chunk(), 13);
+
+    // A new chunk starts over with the full budget.
+    let chunks = converter.go("one two three four five").unwrap();
+    assert_eq!(chunks, vec!["one two three four", "five"]);
+    assert_eq!(converter.remaining_in_last_chunk(), 16);
+
+    // A failed conversion leaves no chunk to append to.
+    let mut converter = Converter::with_config(ConverterConfig {
+        max_len: 20,
+        overflow: OverflowStrategy::Error,
+        ..Default::default()
+    });
+    converter.go("one").unwrap();
+    assert!(converter.go(&"x".repeat(30)).is_err());
+    assert_eq!(converter.remaining_in_last_chunk(), 0);
+
+    // Counted in the configured unit, after the continuation markers.
+    let mut converter = Converter::with_config(ConverterConfig {
+        max_len: 20,
+        length_metric: LengthMetric::Utf16,
+        chunk_continuation: Some((" ↩".to_string(), "↪ ".to_string())),
+        ..Default::default()
+    });
+    let chunks = converter.go("one two three four five 😀").unwrap();
+    assert_eq!(chunks.last().unwrap(), "↪ four five 😀");
+    assert_eq!(converter.remaining_in_last_chunk(), 6);
+}
+
+#[test]
+fn keeps_code_span_inside_link_label() {
+    transform_expect_1(
+        "see [`a_b()`](https://docs.rs) or [call `f` now](https://x.y)",
+        "see [`a_b()`](https://docs\\.rs) or [call `f` now](https://x\\.y)",
+    );
+}
+
+#[test]
+fn drops_rules_at_document_edges() {
+    transform_expect_1("---\n\ntext", "text");
+    transform_expect_1("text\n\n***\n", "text");
+    transform_expect_1("> ---\n\ntext", "text");
+    assert!(Converter::default().go("---").unwrap().is_empty());
+}
+
+#[test]
+fn renders_details_as_expandable_quote() {
+    transform_expect_1(
+        "before\n\n<details>\n<summary>Click me</summary>\n\nHidden **text** here.\n\nMore.\n\n</details>\n\nafter",
+        "before\n\n**>*Click me*\n>Hidden *text* here\\.\n>\n>More\\.||\n\nafter",
+    );
+    transform_expect_n(
+        "<details open><summary>Click me</summary>\n\nHidden words here.\n\n</details>",
+        "**>*Click me*\n>Hidden||===**>words here\\.||",
+        24,
+    );
+}
+
+#[test]
+fn splits_before_details_without_stray_closers() {
+    let input = "some text here that is longish\n\n<details>\n<summary>S</summary>\n\n\
+                 body **b** text\n\n</details>";
+    transform_expect_n(
+        input,
+        "some text here that is longish===**>*S*\n>body *b* text||",
+        33,
+    );
+    transform_expect_n(
+        input,
+        "some text here that===is longish\n\n**>*S*||===**>body *b* text||",
+        20,
+    );
+    // A quote that would open on the last line of a chunk opens in the next.
+    let convert = |max_len| {
+        Converter::with_config(ConverterConfig {
+            max_len,
+            enable_tables: true,
+            ..Default::default()
+        })
+        .go(
+            "| a | b |\n|---|---|\n| x \\| y | z |\n\n<details>\n<summary>Sum</summary>\n\n\
+             body text\n\n</details>",
+        )
+        .unwrap()
+    };
+    assert_eq!(
+        convert(30),
+        vec!["*a*: x \\| y · *b*: z", "**>*Sum*\n>body text||"]
+    );
+    for max_len in 10..80 {
+        let mut converter = Converter::new(max_len);
+        assert!(converter.go_validated(input).is_ok(), "max_len {max_len}");
+        let html_lines = "text\n\n<details>\n<summary>S\n\nbody **b** text\n\n</details>";
+        assert!(
+            converter.go_validated(html_lines).is_ok(),
+            "max_len {max_len}"
+        );
+    }
+}
+
+#[test]
+fn truncates_to_max_chunks_inside_bold() {
+    let mut converter = Converter::with_config(ConverterConfig {
+        max_len: 20,
+        max_chunks: Some(2),
+        ..Default::default()
+    });
+
+    assert_eq!(
+        converter
+            .go("Intro **bold text that goes on and on and on** tail")
+            .unwrap(),
+        vec!["Intro *bold text*", "*that goes on…*"]
+    );
+    assert_eq!(
+        converter.go("short **bold**").unwrap(),
+        vec!["short *bold*"]
+    );
+}
+
+#[test]
+fn test1() -> anyhow::Result<()> {
+    let input = include_str!("1-input.md");
+    let chunks = Converter::default().go(input)?;
+    let actual = chunks.join("===");
+    let _expected = include_str!("1-output.txt");
+
+    std::fs::write("tests/1-output.txt", &actual).unwrap();
+    //assert_eq!(actual, expected);
+
+    Ok(())
+}
+
+#[test]
+fn test2() -> anyhow::Result<()> {
+    let input = include_str!("2-input.md");
+    let chunks = Converter::default().go(input)?;
+    let actual = chunks.join("===");
+    let _expected = include_str!("2-output.txt");
+
+    std::fs::write("tests/2-output.txt", &actual).unwrap();
+    //assert_eq!(actual, expected);
+
+    Ok(())
+}
+
+#[test]
+fn test3() -> anyhow::Result<()> {
+    let input = include_str!("3-input.md");
+    let chunks = Converter::default().go(input)?;
+    let actual = chunks.join("===");
+    let _expected = include_str!("3-output.txt");
+
+    std::fs::write("tests/3-output.txt", &actual).unwrap();
+    // assert_eq!(actual, expected);
+
+    Ok(())
+}
+
+#[test]
+fn test4() -> anyhow::Result<()> {
+    let input = include_str!("4-input.md");
+    let chunks = Converter::default().go(input)?;
+    let actual = chunks.join("===");
+    let _expected = include_str!("4-output.txt");
+
+    std::fs::write("tests/4-output.txt", &actual).unwrap();
+    //assert_eq!(actual, expected);
+
+    Ok(())
+}
+
+#[test]
+fn test5() -> anyhow::Result<()> {
+    let input = include_str!("5-input.md");
+    let chunks = Converter::default().go(input)?;
+    let actual = chunks.join("===");
+    let _expected = include_str!("5-output.txt");
+
+    std::fs::write("tests/5-output.txt", &actual).unwrap();
+    //assert_eq!(actual, expected);
+
+    Ok(())
+}
+
+#[test]
+fn test6() -> anyhow::Result<()> {
+    let input = include_str!("6-input.md");
+    let chunks = Converter::default().go(input)?;
+    let actual = chunks.join("===");
+    let _expected = include_str!("6-output.txt");
+
+    std::fs::write("tests/6-output.txt", &actual).unwrap();
+    //assert_eq!(actual, expected);
+
+    Ok(())
+}
+#[test]
+fn test7() -> anyhow::Result<()> {
+    let input = include_str!("7-input.md");
+    let chunks = Converter::default().go(input)?;
+    let actual = chunks.join("===");
+    let _expected = include_str!("7-output.txt");
+
+    std::fs::write("tests/7-output.txt", &actual).unwrap();
+    //assert_eq!(actual, expected);
+
+    Ok(())
+}
+
+#[test]
+fn test8() -> anyhow::Result<()> {
+    let input = include_str!("8-input.md");
+    let chunks = Converter::default().go(input)?;
+    let actual = chunks.join("===");
+    let _expected = include_str!("8-output.txt");
+
+    std::fs::write("tests/8-output.txt", &actual).unwrap();
+    //assert_eq!(actual, expected);
+
+    Ok(())
+}
+
+#[test]
+fn test9() -> anyhow::Result<()> {
+    let input = include_str!("9-input.md");
+    let chunks = Converter::default().go(input)?;
+    let actual = chunks.join("===");
+    let _expected = include_str!("9-output.txt");
+
+    std::fs::write("tests/9-output.txt", &actual).unwrap();
+    //assert_eq!(actual, expected);
+
+    Ok(())
 }
 
 #[test]
@@ -452,3 +2323,88 @@ fn test10() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn hard_splits_long_word_inside_entity() {
+    transform_expect_n(
+        "x `aaaaaaaaaaaaaaaaaaaaaaaaa`",
+        "x `aaaaaaaaaaaaaaaaa`===`aaaaaaaa`",
+        21,
+    );
+    transform_expect_n(
+        "**aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa**",
+        "*aaaaaaaaaaaaaaaaaaa*===*aaaaaaaaaaaaaaaa*",
+        21,
+    );
+}
+
+#[test]
+fn hard_splits_long_word_between_character_clusters() {
+    let family = "👨\u{200d}👩\u{200d}👧\u{200d}👦";
+    transform_expect_n(
+        &format!("ab {family}{family}"),
+        &format!("ab==={family}==={family}"),
+        30,
+    );
+    transform_expect_n(
+        "abcdefgh e\u{301}e\u{301}e\u{301}",
+        "abcdefgh===e\u{301}e\u{301}===e\u{301}",
+        8,
+    );
+}
+
+#[test]
+fn hard_splits_long_tokens_without_whitespace() {
+    let base64: String = (0..5000).map(|i| b"AZaz09+/"[i % 8] as char).collect();
+    let chunks = Converter::default()
+        .go(&format!("sha: {base64} end"))
+        .unwrap();
+    assert_eq!(chunks.len(), 3);
+    assert_eq!(chunks[0], "sha:");
+    assert_eq!(chunks[1].len(), 4096);
+    assert_eq!(
+        chunks.concat(),
+        format!("sha:{base64} end").replace('+', "\\+")
+    );
+
+    // Every chunk ends on a char boundary, whatever the width of the chars.
+    let mixed = "é日🙂a".repeat(400);
+    let chunks = Converter::new(13).go(&mixed).unwrap();
+    assert_eq!(&chunks[..2], ["é日🙂aé", "日🙂aé日"]);
+    assert_eq!(chunks.concat(), mixed);
+}
+
+#[test]
+fn breaks_long_words_at_soft_hyphens() {
+    let input = "Donau\u{ad}dampf\u{ad}schiff\u{ad}fahrts\u{ad}gesellschaft `a\u{ad}b`";
+    transform_expect_n(
+        input,
+        "Donaudampf\u{ad}===schifffahrts\u{ad}===gesellschaft `a\u{ad}b`",
+        20,
+    );
+    transform_expect_1(input, "Donaudampfschifffahrtsgesellschaft `a\u{ad}b`");
+}
+
+#[test]
+fn survives_inputs_found_by_fuzzing() {
+    transform_expect_1("</details>\n\nafter", "after");
+    // More quote levels than a `u8` counts.
+    let deep = ">".repeat(300) + " hi";
+    transform_expect_1(&deep, &(">".repeat(300) + "hi"));
+    assert_eq!(
+        Converter::new(100)
+            .go(&deep)
+            .unwrap_err()
+            .downcast::<Error>()
+            .unwrap(),
+        Error::ChunkTooSmall {
+            needed: 301,
+            max_len: 100
+        }
+    );
+    transform_expect_n(
+        "```aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\ncode\n```",
+        "```\ncode\n```",
+        21,
+    );
+}